@@ -0,0 +1,78 @@
+use std::time::Instant;
+
+use axum::http::{header, HeaderMap, Request};
+use axum::Json;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::{json, Value};
+
+use crate::{ARGS, build_client, CONFIG, default_model, envelope, messages, resolve_api_key, time_diff};
+
+// 管理接口需要 Authorization: Bearer <admin_token>,未配置 --admin-token 时不可用
+pub async fn admin_auth<B>(req: Request<B>, next: Next<B>) -> Response {
+    if ARGS.get().unwrap().admin_token.is_none() {
+        return Json(envelope(json!({
+            "message":messages::get("admin_disabled"),
+            "code":403u16,
+        }))).into_response();
+    }
+    if !authorized(req.headers()) {
+        return Json(envelope(json!({
+            "message":messages::get("unauthorized"),
+            "code":401u16,
+        }))).into_response();
+    }
+    next.run(req).await
+}
+
+pub fn authorized(headers: &HeaderMap) -> bool {
+    let token = match &ARGS.get().unwrap().admin_token {
+        None => { return false; }
+        Some(token) => { token }
+    };
+    headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| value == token)
+        .unwrap_or(false)
+}
+
+pub async fn config_handler() -> Json<Value> {
+    Json(envelope(json!({
+        "code":200u16,
+        "default_model":default_model(),
+        "args":ARGS.get().unwrap(),
+        "config":CONFIG.get().unwrap(),
+    })))
+}
+
+// 请求一次 OpenAI 的 models 接口(不消耗 token),返回是否成功和耗时
+pub async fn ping_handler() -> Json<Value> {
+    let api_key = match resolve_api_key("") {
+        None => {
+            return Json(envelope(json!({
+                "message":messages::get("api_key_empty"),
+                "code":500u16,
+            })));
+        }
+        Some(key) => { key }
+    };
+    let start_time = Instant::now();
+    let result = build_client(api_key, "").models().list().await;
+    let latency_ms = time_diff(start_time, Instant::now()).as_millis();
+    match result {
+        Ok(_) => {
+            Json(envelope(json!({
+                "code":200u16,
+                "latency_ms":latency_ms,
+            })))
+        }
+        Err(err) => {
+            Json(envelope(json!({
+                "message":format!("{}",err),
+                "code":502u16,
+                "latency_ms":latency_ms,
+            })))
+        }
+    }
+}
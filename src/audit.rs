@@ -0,0 +1,70 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+use anyhow::Result;
+use async_openai::types::CompletionUsage;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use tracing::error;
+
+use crate::{ARGS, AUDIT_LOG, key_source, resolve_api_key, UserInput};
+
+// 审计日志,每个请求一行 JSON;默认不记录消息内容
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { file: Mutex::new(file) })
+    }
+
+    pub fn record(&self, request_id: &str, input: &UserInput, code: u16, usage: Option<&CompletionUsage>, response: Option<&str>) {
+        let mut entry = json!({
+            "timestamp":chrono::Utc::now().to_rfc3339(),
+            "request_id":request_id,
+            "key":resolve_api_key(&input.api_key).map(|key| key_id(&key)),
+            "key_source":key_source(&input.api_key),
+            "model":input.model,
+            "code":code,
+            "usage":usage,
+        });
+        if let Some(metadata) = &input.metadata {
+            entry["metadata"] = json!(metadata);
+        }
+        if ARGS.get().unwrap().audit_log_content {
+            entry["contents"] = json!(input.contents.iter()
+                .map(|content| json!({"role":content.role,"content":content.content}))
+                .collect::<Vec<_>>());
+            entry["response"] = json!(response);
+        }
+        let mut file = self.file.lock();
+        if let Err(err) = writeln!(file, "{}", entry) {
+            error!("Unable to write audit log: {}", err);
+        }
+    }
+}
+
+// 没有开启 --audit-log 时不记录
+pub fn record(request_id: &str, input: &UserInput, code: u16, usage: Option<&CompletionUsage>, response: Option<&str>) {
+    if let Some(audit_log) = AUDIT_LOG.get() {
+        audit_log.record(request_id, input, code, usage, response);
+    }
+}
+
+// 按响应体({"message":..,"code":..,"usage":..})记录一行并原样返回响应体,
+// 用于发往 OpenAI 之前就拒绝的请求和 /chat/compare、/summarize 的结果
+pub fn log(request_id: &str, input: &UserInput, body: Value) -> Value {
+    let code = body["code"].as_u64().unwrap_or(500) as u16;
+    let usage = serde_json::from_value::<CompletionUsage>(body["usage"].clone()).ok();
+    let response = body["message"].as_str().filter(|_| code == 200);
+    record(request_id, input, code, usage.as_ref(), response);
+    body
+}
+
+// 只保留密钥末尾 4 位用于区分
+fn key_id(key: &str) -> String {
+    let tail: String = key.chars().rev().take(4).collect::<Vec<_>>().into_iter().rev().collect();
+    format!("...{}", tail)
+}
@@ -0,0 +1,87 @@
+use std::convert::Infallible;
+
+use axum::body::StreamBody;
+use axum::extract::Query;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::request_id::{Peer, RequestId};
+use crate::{ARGS, ChatContext, ChatParams, envelope, extract, hooks, messages, MIDDLEWARES, UserInput};
+
+// {"requests":[{..}, {..}]} 每一项与 /chat 的请求体相同
+#[derive(Deserialize, Debug)]
+pub struct BatchInput {
+    pub requests: Vec<UserInput>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BatchParams {
+    #[serde(default)]
+    pub stream: bool,
+}
+
+// 默认全部完成后按顺序返回 results;?stream=true 时以 ndjson 逐条返回,先完成的先返回,
+// 每行带 index 标明对应第几个请求
+pub async fn batch_handler(Extension(request_id): Extension<RequestId>, Extension(peer): Extension<Peer>, Query(params): Query<BatchParams>, headers: axum::http::HeaderMap, Json(mut value): Json<Value>) -> Response {
+    if let Some(requests) = value.get_mut("requests").and_then(Value::as_array_mut) {
+        for request in requests {
+            if let Err(response) = extract::restrict_fields(request, &[]) {
+                return response.into_response();
+            }
+        }
+    }
+    let batch: BatchInput = match serde_json::from_value(value) {
+        Ok(s) => { s }
+        Err(err) => {
+            return Json(envelope(json!({
+                "message":format!("{}",err),
+                "code":400u16,
+            }))).into_response();
+        }
+    };
+    if batch.requests.is_empty() {
+        return Json(envelope(json!({
+            "message":messages::get("requests_empty"),
+            "code":400u16,
+        }))).into_response();
+    }
+
+    let concurrency = ARGS.get().unwrap().batch_concurrency.max(1);
+    let results = futures::stream::iter(batch.requests.into_iter().enumerate())
+        .map(move |(index, input)| {
+            // 每一项单独注册,可用 <request_id>-<index> 取消
+            let ctx = ChatContext {
+                route: None,
+                request_id: RequestId(format!("{}-{}", request_id.0, index)),
+                peer,
+                params: ChatParams::default(),
+                headers: headers.clone(),
+                schema_attempt: 0,
+                empty_retried: false,
+            };
+            async move { (index, hooks::run(MIDDLEWARES.get().unwrap(), input, ctx).await) }
+        })
+        .buffer_unordered(concurrency);
+
+    if params.stream {
+        let lines = results.map(|(index, result)| {
+            let mut line = envelope(result);
+            line["index"] = json!(index);
+            Ok::<_, Infallible>(format!("{}\n", line))
+        });
+        return ([(header::CONTENT_TYPE, "application/x-ndjson")], StreamBody::new(lines)).into_response();
+    }
+
+    let mut results: Vec<(usize, Value)> = results.collect().await;
+    results.sort_by_key(|(index, _)| *index);
+    // 每一项与 ?stream=true 时的每一行一样转换为统一响应格式
+    let results: Vec<Value> = results.into_iter().map(|(_, result)| envelope(result)).collect();
+    Json(envelope(json!({
+        "code":200u16,
+        "results":results,
+    }))).into_response()
+}
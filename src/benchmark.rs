@@ -0,0 +1,72 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::{ARGS, build_chat_request, build_client, examples, resolve_api_key, time_diff, UserInput};
+
+pub async fn run(requests: usize, concurrency: usize, model: String, prompt: String, max_tokens: u32) -> Result<()> {
+    let api_key = resolve_api_key("").ok_or_else(|| anyhow!("api_key is empty"))?;
+    let input: UserInput = serde_json::from_value(json!({
+        "model":model,
+        "max_tokens":max_tokens,
+        "contents":[{"role":"user","content":prompt}],
+    }))?;
+    let request = build_chat_request(&input, examples(None))?;
+    let client = build_client(api_key, &input.model);
+    // 每个请求最多等待 --timeout 秒,超时计为失败
+    let timeout = Duration::from_secs(ARGS.get().unwrap().timeout);
+
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    let start_time = Instant::now();
+    for _ in 0..requests {
+        let permit = semaphore.clone().acquire_owned().await?;
+        let client = client.clone();
+        let request = request.clone();
+        tasks.spawn(async move {
+            let start_time = Instant::now();
+            let result = tokio::time::timeout(timeout, client.chat().create(request)).await;
+            drop(permit);
+            (time_diff(start_time, Instant::now()), matches!(result, Ok(Ok(_))))
+        });
+    }
+
+    let mut latencies: Vec<Duration> = Vec::new();
+    let mut errors = 0usize;
+    while let Some(res) = tasks.join_next().await {
+        let (duration, ok) = res?;
+        if ok {
+            latencies.push(duration);
+        } else {
+            errors += 1;
+        }
+    }
+    let total = time_diff(start_time, Instant::now());
+    latencies.sort();
+
+    println!("{:<12}{}", "model", input.model);
+    println!("{:<12}{}", "requests", requests);
+    println!("{:<12}{}", "concurrency", concurrency);
+    println!("{:<12}{:.2}s", "total", total.as_secs_f64());
+    println!("{:<12}{}", "success", latencies.len());
+    println!("{:<12}{} ({:.2}%)", "errors", errors, errors as f64 * 100.0 / requests.max(1) as f64);
+    for (name, p) in [("p50", 50), ("p90", 90), ("p99", 99), ("max", 100)] {
+        match percentile(&latencies, p) {
+            None => println!("{:<12}-", name),
+            Some(d) => println!("{:<12}{}ms", name, d.as_millis()),
+        }
+    }
+    Ok(())
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let idx = ((sorted.len() * p).div_ceil(100)).clamp(1, sorted.len()) - 1;
+    Some(sorted[idx])
+}
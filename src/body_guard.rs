@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use axum::body::{Body, Bytes, HttpBody};
+use axum::http::{header, Method, Request, StatusCode};
+use axum::Json;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+use tracing::debug;
+
+use crate::{ARGS, envelope, messages};
+
+// 防止慢速发送请求体(slow-body)占住连接: 在 --body-read-timeout 内读完整个请求体,
+// 超过 --max-body-size 时直接拒绝;--require-content-length 时不接受没有 Content-Length 的分块请求体
+pub async fn body_guard(req: Request<Body>, next: Next<Body>) -> Response {
+    if req.method() != Method::POST {
+        return next.run(req).await;
+    }
+    let args = ARGS.get().unwrap();
+    let content_length = req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    match content_length {
+        None if args.require_content_length => {
+            debug!("request body without content-length rejected");
+            return error(StatusCode::LENGTH_REQUIRED, "length_required");
+        }
+        Some(len) if len > args.max_body_size => {
+            debug!("request body of {} bytes rejected", len);
+            return error(StatusCode::PAYLOAD_TOO_LARGE, "body_too_large");
+        }
+        _ => {}
+    }
+    if args.body_read_timeout == 0 {
+        return next.run(req).await;
+    }
+    let (parts, body) = req.into_parts();
+    let bytes = match tokio::time::timeout(Duration::from_secs(args.body_read_timeout), read(body, args.max_body_size)).await {
+        Ok(Ok(bytes)) => { bytes }
+        Ok(Err(status)) => {
+            let key = if status == StatusCode::PAYLOAD_TOO_LARGE { "body_too_large" } else { "invalid_request" };
+            return error(status, key);
+        }
+        Err(_) => {
+            debug!("request body not received within {}s", args.body_read_timeout);
+            return error(StatusCode::REQUEST_TIMEOUT, "body_timeout");
+        }
+    };
+    next.run(Request::from_parts(parts, Body::from(bytes))).await
+}
+
+// 读完请求体,累计超过 limit 时停止读取
+async fn read(mut body: Body, limit: u64) -> Result<Bytes, StatusCode> {
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if (buf.len() + chunk.len()) as u64 > limit {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(buf))
+}
+
+fn error(status: StatusCode, key: &str) -> Response {
+    Json(envelope(json!({
+        "message":messages::get(key),
+        "code":status.as_u16(),
+    }))).into_response()
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use parking_lot::Mutex;
+use tokio::sync::oneshot;
+
+// 进行中的请求: request_id -> 发起方和取消信号
+static IN_FLIGHT: LazyLock<Mutex<HashMap<String, Registered>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+struct Registered {
+    owner: String,
+    sender: oneshot::Sender<()>,
+}
+
+pub struct Cancellation {
+    id: String,
+    pub receiver: oneshot::Receiver<()>,
+}
+
+impl Drop for Cancellation {
+    fn drop(&mut self) {
+        self.receiver.close();
+        let mut in_flight = IN_FLIGHT.lock();
+        // 同一 request_id 可能已被新的请求覆盖,只移除自己的
+        if in_flight.get(&self.id).map(|registered| registered.sender.is_closed()).unwrap_or(false) {
+            in_flight.remove(&self.id);
+        }
+    }
+}
+
+// owner 见 request_id::owner
+pub fn register(id: &str, owner: String) -> Cancellation {
+    let (sender, receiver) = oneshot::channel();
+    IN_FLIGHT.lock().insert(id.to_string(), Registered { owner, sender });
+    Cancellation { id: id.to_string(), receiver }
+}
+
+// 不是同一个发起方时与请求不存在一样处理
+pub fn cancel(id: &str, owner: &str) -> bool {
+    let mut in_flight = IN_FLIGHT.lock();
+    if in_flight.get(id).map(|registered| registered.owner != owner).unwrap_or(true) {
+        return false;
+    }
+    match in_flight.remove(id) {
+        None => false,
+        Some(registered) => registered.sender.send(()).is_ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_owner_can_cancel() {
+        let mut cancellation = register("cancel-owner-test", "key:a".to_string());
+        assert!(!cancel("cancel-owner-test", "key:b"));
+        assert!(!cancel("cancel-owner-test", "ip:127.0.0.1"));
+        assert!(cancel("cancel-owner-test", "key:a"));
+        assert!(cancellation.receiver.try_recv().is_ok());
+    }
+}
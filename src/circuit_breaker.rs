@@ -0,0 +1,132 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tracing::{info, warn};
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { probe_until: Instant },
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    probe_timeout: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration, probe_timeout: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            probe_timeout,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    // threshold 为 0 时不启用熔断
+    pub fn allow(&self) -> bool {
+        if self.threshold == 0 {
+            return true;
+        }
+        let mut state = self.state.lock();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } => {
+                if Instant::now() >= until {
+                    info!("circuit breaker half-open");
+                    *state = State::HalfOpen { probe_until: Instant::now() + self.probe_timeout };
+                    true
+                } else {
+                    false
+                }
+            }
+            // 半开状态下只放行一个探测请求;探测请求被取消或断开而没有报告结果时,超过 probe_timeout 后放行下一个
+            State::HalfOpen { probe_until } => {
+                if Instant::now() >= probe_until {
+                    warn!("circuit breaker probe not reported within {:?}, allowing another", self.probe_timeout);
+                    *state = State::HalfOpen { probe_until: Instant::now() + self.probe_timeout };
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    pub fn state(&self) -> &'static str {
+        if self.threshold == 0 {
+            return "disabled";
+        }
+        match *self.state.lock() {
+            State::Closed { .. } => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half_open",
+        }
+    }
+
+    pub fn on_success(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock();
+        if !matches!(*state, State::Closed { .. }) {
+            info!("circuit breaker closed");
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub fn on_failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock();
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            State::HalfOpen { .. } => self.threshold,
+            State::Open { .. } => return,
+        };
+        if failures >= self.threshold {
+            warn!("circuit breaker open for {:?} after {} failures", self.cooldown, failures);
+            *state = State::Open { until: Instant::now() + self.cooldown };
+        } else {
+            *state = State::Closed { failures };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_open_probe_expires() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO, Duration::from_millis(20));
+        breaker.on_failure();
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), "half_open");
+        assert!(!breaker.allow());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow());
+        breaker.on_success();
+        assert_eq!(breaker.state(), "closed");
+    }
+
+    #[test]
+    fn panic_while_locked_does_not_poison() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30), Duration::from_secs(120));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _state = breaker.state.lock();
+            panic!("handler panicked while holding the breaker lock");
+        }));
+        assert!(result.is_err());
+        assert!(breaker.allow());
+        breaker.on_failure();
+        breaker.on_failure();
+        assert_eq!(breaker.state(), "open");
+    }
+}
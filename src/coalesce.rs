@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// 合并流式增量后再发送,让客户端的显示更平滑:遇到句子结尾立即发送,
+// 否则最多等待 interval,到时只发送到最后一个完整的词为止
+pub struct Coalescer {
+    interval: Option<Duration>,
+    pending: String,
+    deadline: Option<Instant>,
+}
+
+impl Coalescer {
+    // interval 为 0 时每个增量立即发送
+    pub fn new(interval_ms: u64) -> Self {
+        Coalescer {
+            interval: (interval_ms > 0).then(|| Duration::from_millis(interval_ms)),
+            pending: String::new(),
+            deadline: None,
+        }
+    }
+
+    pub fn push(&mut self, delta: String) -> Option<String> {
+        let Some(interval) = self.interval else {
+            return Some(delta);
+        };
+        self.pending.push_str(&delta);
+        if ends_sentence(&self.pending) {
+            return self.take();
+        }
+        if self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + interval);
+        }
+        None
+    }
+
+    // 等待到期;没有待发送的内容时一直等待
+    pub async fn expired(&self) {
+        match self.deadline {
+            None => { std::future::pending().await }
+            Some(deadline) => { tokio::time::sleep_until(deadline).await }
+        }
+    }
+
+    // 到期时发送到最后一个空白为止,剩下的半个词留到下一次;没有空白(如中文)时全部发送
+    pub fn flush_words(&mut self) -> Option<String> {
+        let split = self.pending.rfind(char::is_whitespace)
+            .map(|index| index + self.pending[index..].chars().next().map(char::len_utf8).unwrap_or(1))
+            .filter(|index| *index < self.pending.len());
+        match split {
+            Some(index) => {
+                let rest = self.pending.split_off(index);
+                let head = std::mem::replace(&mut self.pending, rest);
+                self.deadline = self.interval.map(|interval| Instant::now() + interval);
+                Some(head)
+            }
+            None => { self.take() }
+        }
+    }
+
+    pub fn take(&mut self) -> Option<String> {
+        self.deadline = None;
+        (!self.pending.is_empty()).then(|| std::mem::take(&mut self.pending))
+    }
+}
+
+fn ends_sentence(s: &str) -> bool {
+    s.trim_end_matches([' ', '\t']).ends_with(['.', '!', '?', '\n', '。', '\u{ff01}', '\u{ff1f}'])
+}
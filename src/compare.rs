@@ -0,0 +1,151 @@
+use std::time::Instant;
+
+use axum::http::HeaderMap;
+use axum::{Extension, Json};
+use axum::response::{IntoResponse, Response};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{apply_defaults, ARGS, audit, build_chat_request, build_config, ChatRequestError, CIRCUIT_BREAKER, envelope, examples, extract, injection, limit_max_tokens, limit_n, messages, model_allowed, request_timeout, resolve_api_key, status, THROTTLE, time_diff, upstream, UserInput};
+use crate::provider::UpstreamConfig;
+use crate::request_id::RequestId;
+
+// {"models":["gpt-3.5-turbo","gpt-4"],"contents":[..],...} 其余字段与 /chat 相同
+#[derive(Deserialize, Debug)]
+pub struct CompareInput {
+    pub models: Vec<String>,
+    #[serde(flatten)]
+    pub input: UserInput,
+}
+
+pub async fn compare_handler(Extension(request_id): Extension<RequestId>, headers: HeaderMap, Json(mut value): Json<Value>) -> Response {
+    if let Err(response) = extract::restrict_fields(&mut value, &["models"]) {
+        return response.into_response();
+    }
+    match serde_json::from_value::<CompareInput>(value) {
+        Ok(compare) => { compare_models(compare, &headers, &request_id.0).await.into_response() }
+        Err(err) => {
+            Json(envelope(json!({
+                "message":format!("{}",err),
+                "code":400u16,
+            }))).into_response()
+        }
+    }
+}
+
+async fn compare_models(compare: CompareInput, headers: &HeaderMap, request_id: &str) -> Json<Value> {
+    let _active = status::track();
+    if compare.models.is_empty() {
+        return Json(envelope(audit::log(request_id, &compare.input, json!({
+            "message":messages::get("models_empty"),
+            "code":400u16,
+        }))));
+    }
+    if compare.input.stream {
+        return Json(envelope(audit::log(request_id, &compare.input, json!({
+            "message":messages::get("compare_stream_not_supported"),
+            "code":400u16,
+        }))));
+    }
+    if let Some(phrase) = injection::scan(&compare.input) {
+        return Json(envelope(audit::log(request_id, &compare.input, json!({
+            "message":messages::get("prompt_injection"),
+            "code":403u16,
+            "phrase":phrase,
+        }))));
+    }
+    if let Some(model) = compare.models.iter().find(|model| !model_allowed(model)) {
+        return Json(envelope(audit::log(request_id, &compare.input, json!({
+            "message":format!("model {} is not allowed",model),
+            "code":403u16,
+        }))));
+    }
+    let api_key = match resolve_api_key(&compare.input.api_key) {
+        None => {
+            return Json(envelope(audit::log(request_id, &compare.input, json!({
+                "message":messages::get("api_key_empty"),
+                "code":500u16,
+            }))));
+        }
+        Some(key) => { key }
+    };
+
+    let concurrency = ARGS.get().unwrap().compare_concurrency.max(1);
+    let results: Vec<(String, Value)> = futures::stream::iter(compare.models.iter().cloned())
+        .map(|model| {
+            let mut input = compare.input.clone();
+            input.model = model.clone();
+            // 先套用该模型的默认值,再按该模型检查 max_tokens 和 n
+            apply_defaults(&mut input, None);
+            let limited = limit_max_tokens(&mut input, None).and_then(|_| limit_n(&mut input).map(|_| ()));
+            let config = build_config(api_key.clone(), &input.model).forward(headers);
+            async move {
+                let result = match limited {
+                    Ok(_) => { compare_one(&config, &input).await }
+                    Err(err) => { json!({"message":messages::get("invalid_request"),"code":400u16,"errors":[err]}) }
+                };
+                let result = audit::log(request_id, &input, result);
+                (model, result)
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let mut map = Map::new();
+    for (model, result) in results {
+        map.insert(model, envelope(result));
+    }
+    Json(envelope(json!({
+        "code":200u16,
+        "results":map,
+    })))
+}
+
+async fn compare_one(config: &UpstreamConfig, input: &UserInput) -> Value {
+    let request = match build_chat_request(input, examples(None)) {
+        Ok(s) => { s }
+        Err(ChatRequestError::Invalid(errors)) => {
+            return json!({"message":messages::get("invalid_request"),"code":400u16,"errors":errors});
+        }
+        Err(err) => {
+            return json!({"message":format!("{}",err),"code":500u16});
+        }
+    };
+    // 与 /chat 相同: 熔断打开时直接拒绝,按限流信息等待,失败时按 max_retries 重试
+    let breaker = CIRCUIT_BREAKER.get().unwrap();
+    if !breaker.allow() {
+        return json!({"message":messages::get("upstream_unavailable"),"code":503u16});
+    }
+    let throttle = THROTTLE.get().unwrap();
+    throttle.wait().await;
+    let timeout = request_timeout(input);
+    let start_time = Instant::now();
+    let upstream_call = upstream::create_chat_completion_with_retry(config, &request, input);
+    let response = match tokio::time::timeout(timeout, upstream_call).await {
+        Ok(Ok((s, headers))) => {
+            throttle.update(&headers);
+            s
+        }
+        Ok(Err(err)) => {
+            return json!({"message":format!("{}",err),"code":500u16});
+        }
+        Err(_) => {
+            breaker.on_failure();
+            return json!({"message":format!("upstream timeout after {}s",timeout.as_secs()),"code":504u16});
+        }
+    };
+    let content = response.choices.first().and_then(|choice| choice.message.content.clone());
+    match content {
+        None => { json!({"message":messages::get("no_content"),"code":500u16}) }
+        Some(s) => {
+            json!({
+                "message":s,
+                "code":200u16,
+                "usage":response.usage,
+                "elapsed_ms":time_diff(start_time, Instant::now()).as_millis() as u64,
+            })
+        }
+    }
+}
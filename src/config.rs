@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use async_openai::types::CompletionUsage;
+use serde::{Deserialize, Serialize};
+
+use crate::hooks::MiddlewareConfig;
+use crate::messages;
+
+// 配置文件(TOML),例如:
+// [routes.fast]
+// model = "gpt-3.5-turbo"
+// [routes.smart]
+// model = "gpt-4"
+// 会注册 /chat/fast 和 /chat/smart 两个路由
+// [[examples]] 为 few-shot 示例,插在系统提示词之后、客户端消息之前;
+// 路由下的 examples 会覆盖全局的
+// [templates] 为提示词模板,请求中 template 指定名称,{input} 替换为最后一条用户消息
+// [templates]
+// concise = "Answer concisely: {input}"
+// [personas] 为命名的系统提示词,请求中 persona 指定名称,优先于 --system-prompt
+// [personas]
+// tutor = "You are a patient tutor."
+// [languages] 为 --auto-detect-language 使用的系统提示词,键为 ISO 639-3 语言代码
+// [languages]
+// cmn = "请用中文回答"
+// [messages] 覆盖返回给客户端的固定提示,键见 messages.rs
+// [middleware] 为内置的请求/响应扩展,见 hooks.rs
+// allowed_fields 为客户端可以设置的请求字段(须包含 contents),不设置时不限制;
+// 其他字段被忽略,--strict-body 时返回 400
+// allowed_fields = ["contents", "max_tokens"]
+// injection_phrases 为 --block-injection 检查的短语,不设置时使用内置的列表
+// [models."<model>"] 为该模型的默认参数,请求中未指定时使用
+// [models."gpt-4"]
+// temperature = 0.2
+// max_tokens = 1024
+// [prices."<model>"] 为该模型每百万 token 的美元价格,用于在响应中返回 cost_usd
+// [prices."gpt-4o"]
+// input = 2.5
+// output = 10.0
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub examples: Vec<Example>,
+    #[serde(default)]
+    pub templates: HashMap<String, String>,
+    #[serde(default)]
+    pub personas: HashMap<String, String>,
+    #[serde(default)]
+    pub languages: HashMap<String, String>,
+    #[serde(default)]
+    pub models: HashMap<String, ModelDefaults>,
+    #[serde(default)]
+    pub prices: HashMap<String, Price>,
+    #[serde(default)]
+    pub messages: HashMap<String, String>,
+    #[serde(default)]
+    pub middleware: MiddlewareConfig,
+    #[serde(default)]
+    pub injection_phrases: Vec<String>,
+    #[serde(default)]
+    pub allowed_fields: Vec<String>,
+    #[serde(default)]
+    pub routes: HashMap<String, RouteConfig>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RouteConfig {
+    pub model: String,
+    // 该路由的 max_tokens 上限,覆盖 --max-tokens-limit
+    pub max_tokens_limit: Option<u32>,
+    pub examples: Option<Vec<Example>>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ModelDefaults {
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl Price {
+    pub fn cost(&self, usage: &CompletionUsage) -> f64 {
+        let cost = (usage.prompt_tokens as f64 * self.input + usage.completion_tokens as f64 * self.output) / 1_000_000.0;
+        // 去掉浮点误差
+        (cost * 1e9).round() / 1e9
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Example {
+    pub role: String,
+    pub content: String,
+}
+
+// 已被内置接口占用的 /chat/<name>
+const RESERVED_ROUTES: [&str; 5] = ["stream", "stream.txt", "cancel", "compare", "batch"];
+
+pub fn load(path: &str) -> Result<Config> {
+    let content = fs::read_to_string(path)?;
+    let config: Config = toml::from_str(&content)?;
+    let errors = validate(&config);
+    if !errors.is_empty() {
+        return Err(anyhow!("{}", errors.join("; ")));
+    }
+    Ok(config)
+}
+
+// 供 validate-config 子命令使用,返回全部问题
+pub fn check(path: &str) -> Vec<String> {
+    let content = match fs::read_to_string(path) {
+        Ok(s) => { s }
+        Err(err) => { return vec![format!("{}", err)]; }
+    };
+    match toml::from_str::<Config>(&content) {
+        Ok(config) => { validate(&config) }
+        Err(err) => { vec![format!("{}", err)] }
+    }
+}
+
+fn validate(config: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+    validate_examples("examples", &config.examples, &mut errors);
+    for (name, template) in &config.templates {
+        if !template.contains("{input}") {
+            errors.push(format!("template {}: must contain {{input}}", name));
+        }
+    }
+    for code in config.languages.keys() {
+        if whatlang::Lang::from_code(code).is_none() {
+            errors.push(format!("languages: unknown language code {:?}", code));
+        }
+    }
+    if !config.allowed_fields.is_empty() && !config.allowed_fields.iter().any(|field| field == "contents") {
+        errors.push("allowed_fields: must include contents".to_string());
+    }
+    for key in config.messages.keys() {
+        if !messages::DEFAULTS.iter().any(|(name, _)| name == key) {
+            errors.push(format!("messages: unknown key {:?}", key));
+        }
+    }
+    for (model, defaults) in &config.models {
+        if let Some(temperature) = defaults.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                errors.push(format!("models {}: temperature must be between 0 and 2", model));
+            }
+        }
+    }
+    for (model, price) in &config.prices {
+        if !(price.input >= 0.0 && price.output >= 0.0) {
+            errors.push(format!("prices {}: input and output must not be negative", model));
+        }
+    }
+    for (name, route) in &config.routes {
+        if name.is_empty() || RESERVED_ROUTES.contains(&name.as_str()) || name.contains('/') {
+            errors.push(format!("invalid route name: {:?}", name));
+        }
+        if route.model.is_empty() {
+            errors.push(format!("route {}: model must not be empty", name));
+        }
+        if let Some(examples) = &route.examples {
+            validate_examples(&format!("route {}: examples", name), examples, &mut errors);
+        }
+    }
+    errors
+}
+
+fn validate_examples(prefix: &str, examples: &[Example], errors: &mut Vec<String>) {
+    for (i, example) in examples.iter().enumerate() {
+        if example.role != "user" && example.role != "assistant" {
+            errors.push(format!("{}[{}]: role must be user or assistant", prefix, i));
+        }
+    }
+}
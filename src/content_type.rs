@@ -0,0 +1,35 @@
+use axum::http::{header, HeaderValue, Method, Request};
+use axum::Json;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+use tracing::debug;
+
+use crate::{ARGS, envelope, messages};
+
+// 带请求体却没有 Content-Type 的 POST 请求:
+// --lenient-content-type 时按 JSON 处理,否则返回明确的 400,而不是 axum 默认的 415 纯文本
+pub async fn content_type<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    if req.method() != Method::POST || req.headers().contains_key(header::CONTENT_TYPE) || !has_body(&req) {
+        return next.run(req).await;
+    }
+    if ARGS.get().unwrap().lenient_content_type {
+        debug!("missing content-type, treated as application/json");
+        req.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        return next.run(req).await;
+    }
+    Json(envelope(json!({
+        "message":messages::get("missing_content_type"),
+        "code":400u16,
+    }))).into_response()
+}
+
+fn has_body<B>(req: &Request<B>) -> bool {
+    let content_length = req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    match content_length {
+        Some(len) => { len > 0 }
+        None => { req.headers().contains_key(header::TRANSFER_ENCODING) }
+    }
+}
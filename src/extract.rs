@@ -0,0 +1,152 @@
+use axum::{async_trait, Form, Json};
+use axum::extract::FromRequest;
+use axum::http::{header, Request};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::{ARGS, CONFIG, envelope, UserInput};
+
+// 请求体提取器;--strict-body 时拒绝未知字段和 allowed_fields 以外的字段并列出这些字段
+// 也接受 application/x-www-form-urlencoded,映射为只有一条消息的请求
+pub struct ChatInput(pub UserInput);
+
+// content=你好&max_tokens=100
+#[derive(Deserialize, Debug)]
+struct FormInput {
+    api_key: Option<String>,
+    model: Option<String>,
+    #[serde(default = "default_role")]
+    role: String,
+    content: String,
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    timeout_secs: Option<u64>,
+}
+
+fn default_role() -> String {
+    "user".to_string()
+}
+
+impl From<FormInput> for Value {
+    fn from(form: FormInput) -> Self {
+        let mut value = json!({
+            "contents":[{"role":form.role,"content":form.content}],
+            "max_tokens":form.max_tokens,
+            "temperature":form.temperature,
+            "timeout_secs":form.timeout_secs,
+        });
+        if let Some(api_key) = form.api_key {
+            value["api_key"] = json!(api_key);
+        }
+        if let Some(model) = form.model {
+            value["model"] = json!(model);
+        }
+        value
+    }
+}
+
+#[async_trait]
+impl<S, B> FromRequest<S, B> for ChatInput
+where
+    Json<Value>: FromRequest<S, B>,
+    Json<UserInput>: FromRequest<S, B>,
+    Form<FormInput>: FromRequest<S, B>,
+    B: Send + 'static,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let is_form = req.headers().get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.starts_with("application/x-www-form-urlencoded"))
+            .unwrap_or(false);
+        if is_form {
+            let form = match Form::<FormInput>::from_request(req, state).await {
+                Ok(Form(form)) => { form }
+                Err(err) => { return Err(err.into_response()); }
+            };
+            let mut value: Value = form.into();
+            restrict_fields(&mut value, &[]).map_err(IntoResponse::into_response)?;
+            return match serde_json::from_value::<UserInput>(value) {
+                Ok(input) => { Ok(ChatInput(input)) }
+                Err(err) => {
+                    Err(Json(envelope(json!({
+                        "message":format!("{}",err),
+                        "code":400u16,
+                    }))).into_response())
+                }
+            };
+        }
+        if !ARGS.get().unwrap().strict_body && CONFIG.get().unwrap().allowed_fields.is_empty() {
+            return match Json::<UserInput>::from_request(req, state).await {
+                Ok(Json(input)) => { Ok(ChatInput(input)) }
+                Err(err) => { Err(err.into_response()) }
+            };
+        }
+        let mut value = match Json::<Value>::from_request(req, state).await {
+            Ok(Json(value)) => { value }
+            Err(err) => { return Err(err.into_response()); }
+        };
+        restrict_fields(&mut value, &[]).map_err(IntoResponse::into_response)?;
+        if !ARGS.get().unwrap().strict_body {
+            return match serde_json::from_value::<UserInput>(value) {
+                Ok(input) => { Ok(ChatInput(input)) }
+                Err(err) => {
+                    Err(Json(envelope(json!({
+                        "message":format!("{}",err),
+                        "code":400u16,
+                    }))).into_response())
+                }
+            };
+        }
+        let mut unknown = Vec::new();
+        let input: UserInput = match serde_ignored::deserialize(value, |path| unknown.push(path.to_string())) {
+            Ok(s) => { s }
+            Err(err) => {
+                return Err(Json(envelope(json!({
+                    "message":format!("{}",err),
+                    "code":400u16,
+                }))).into_response());
+            }
+        };
+        if !unknown.is_empty() {
+            return Err(Json(envelope(json!({
+                "message":format!("unknown fields: {}",unknown.join(", ")),
+                "code":400u16,
+                "unknown_fields":unknown,
+            }))).into_response());
+        }
+        Ok(ChatInput(input))
+    }
+}
+
+// 配置了 allowed_fields 时只保留其中的字段(extra 为接口自己的字段,如 /chat/compare 的 models),
+// 其余字段忽略;--strict-body 时返回 400
+pub fn restrict_fields(value: &mut Value, extra: &[&str]) -> Result<(), Json<Value>> {
+    let allowed = &CONFIG.get().unwrap().allowed_fields;
+    let Some(object) = value.as_object_mut().filter(|_| !allowed.is_empty()) else {
+        return Ok(());
+    };
+    let forbidden: Vec<String> = object.iter()
+        .filter(|(key, value)| !value.is_null() && !allowed.contains(key) && !extra.contains(&key.as_str()))
+        .map(|(key, _)| key.clone())
+        .collect();
+    if forbidden.is_empty() {
+        return Ok(());
+    }
+    if ARGS.get().unwrap().strict_body {
+        return Err(Json(envelope(json!({
+            "message":format!("fields not allowed: {}",forbidden.join(", ")),
+            "code":400u16,
+            "forbidden_fields":forbidden,
+        }))));
+    }
+    debug!("ignored fields: {}", forbidden.join(", "));
+    for key in &forbidden {
+        object.remove(key);
+    }
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{chat, ChatContext, UserInput};
+
+// 非流式请求的扩展点:before 在请求发往 OpenAI 之前修改请求,after 修改返回给客户端的响应。
+// after 收到的是统一响应格式转换之前的内容,即 {"message":..,"code":..,..}。
+// 多个实现按注册顺序执行 before,按相反顺序执行 after。
+// 新的实现在 register 中根据配置加入即可。
+pub trait RequestMiddleware: Send + Sync {
+    fn before(&self, _input: &mut UserInput) {}
+    fn after(&self, _body: &mut Value) {}
+}
+
+// 配置文件中的 [middleware] 段
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct MiddlewareConfig {
+    pub prompt_prefix: Option<String>,
+    #[serde(default)]
+    pub profanity_words: Vec<String>,
+}
+
+pub fn register(config: &MiddlewareConfig) -> Vec<Box<dyn RequestMiddleware>> {
+    let mut middlewares: Vec<Box<dyn RequestMiddleware>> = Vec::new();
+    if let Some(prefix) = &config.prompt_prefix {
+        middlewares.push(Box::new(PromptPrefixer { prefix: prefix.clone() }));
+    }
+    if !config.profanity_words.is_empty() {
+        middlewares.push(Box::new(ProfanityFilter { words: config.profanity_words.clone() }));
+    }
+    middlewares
+}
+
+pub async fn run(middlewares: &[Box<dyn RequestMiddleware>], mut input: UserInput, ctx: ChatContext) -> Value {
+    for middleware in middlewares {
+        middleware.before(&mut input);
+    }
+    let mut body = chat(input, ctx).await.0;
+    for middleware in middlewares.iter().rev() {
+        middleware.after(&mut body);
+    }
+    body
+}
+
+// 在最后一条消息前加上固定的前缀
+struct PromptPrefixer {
+    prefix: String,
+}
+
+impl RequestMiddleware for PromptPrefixer {
+    fn before(&self, input: &mut UserInput) {
+        if let Some(content) = input.contents.last_mut() {
+            content.content = format!("{}{}", self.prefix, content.content);
+        }
+    }
+}
+
+// 把回答中的敏感词替换为同样长度的 *,不区分大小写
+struct ProfanityFilter {
+    words: Vec<String>,
+}
+
+impl RequestMiddleware for ProfanityFilter {
+    fn after(&self, body: &mut Value) {
+        let Some(message) = body["message"].as_str() else { return; };
+        let mut message = message.to_string();
+        for word in &self.words {
+            message = mask(&message, word);
+        }
+        body["message"] = Value::String(message);
+    }
+}
+
+// 逐个字符比较(各自转小写),不按字节位置切分,转小写后长度变化的字符(如 İ)也不会切到字符中间
+fn mask(message: &str, word: &str) -> String {
+    let word: Vec<char> = word.chars().collect();
+    let chars: Vec<char> = message.chars().collect();
+    let mut result = String::with_capacity(message.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let matched = !word.is_empty() && i + word.len() <= chars.len()
+            && chars[i..i + word.len()].iter().zip(&word).all(|(a, b)| a.to_lowercase().eq(b.to_lowercase()));
+        if matched {
+            result.push_str(&"*".repeat(word.len()));
+            i += word.len();
+        } else {
+            result.push(chars[i]);
+            i += 1;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn profanity_filter_is_char_aware() {
+        let filter = ProfanityFilter { words: vec!["shit".to_string()] };
+        let mut body = json!({"message":"İshitẞ","code":200});
+        filter.after(&mut body);
+        assert_eq!(body["message"], "İ****ẞ");
+        let mut body = json!({"message":"Oh SHIT, oh Shit","code":200});
+        filter.after(&mut body);
+        assert_eq!(body["message"], "Oh ****, oh ****");
+    }
+}
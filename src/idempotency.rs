@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use tokio::sync::OnceCell;
+use tracing::debug;
+
+use crate::{messages, UserInput};
+
+// Idempotency-Key -> (过期时间, 请求体的哈希, 响应);同一个 key 的并发请求等待第一个请求的结果
+type Entry = (Instant, u64, Arc<OnceCell<Value>>);
+
+static RESPONSES: LazyLock<Mutex<HashMap<String, Entry>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// 同一个 key 带了不同的请求体时返回 422,而不是返回另一个请求的响应
+pub async fn run<F>(key: String, fingerprint: u64, ttl: Duration, f: F) -> Value
+where
+    F: Future<Output = Value>,
+{
+    let cell = {
+        let mut responses = RESPONSES.lock();
+        let now = Instant::now();
+        responses.retain(|_, (expire_at, _, _)| *expire_at > now);
+        let (_, cached_fingerprint, cell) = responses.entry(key.clone())
+            .or_insert_with(|| (now + ttl, fingerprint, Arc::new(OnceCell::new())));
+        if *cached_fingerprint != fingerprint {
+            debug!("idempotency key reused with a different body");
+            return json!({
+                "message":messages::get("idempotency_key_reused"),
+                "code":422u16,
+            });
+        }
+        cell.clone()
+    };
+    if cell.initialized() {
+        debug!("idempotency key hit");
+    }
+    let response = cell.get_or_init(|| f).await.clone();
+    // 只缓存成功的响应,失败时允许客户端重试
+    if response["code"] != 200 {
+        let mut responses = RESPONSES.lock();
+        if responses.get(&key).map(|(_, _, cached)| Arc::ptr_eq(cached, &cell)).unwrap_or(false) {
+            responses.remove(&key);
+        }
+    }
+    response
+}
+
+// 请求体的哈希,用于发现重复使用的 Idempotency-Key
+pub fn fingerprint(input: &UserInput) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(input).unwrap_or_default().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reused_key_with_different_body_is_rejected() {
+        let ttl = Duration::from_secs(60);
+        let first = run("ip:127.0.0.1:k".to_string(), 1, ttl, async { json!({"message":"a","code":200u16}) }).await;
+        assert_eq!(first["message"], "a");
+        let repeated = run("ip:127.0.0.1:k".to_string(), 1, ttl, async { json!({"message":"b","code":200u16}) }).await;
+        assert_eq!(repeated["message"], "a");
+        let reused = run("ip:127.0.0.1:k".to_string(), 2, ttl, async { json!({"message":"c","code":200u16}) }).await;
+        assert_eq!(reused["code"], 422);
+    }
+}
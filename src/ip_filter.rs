@@ -0,0 +1,41 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::Json;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use ipnet::IpNet;
+use serde_json::json;
+use tracing::debug;
+
+use crate::{ARGS, envelope, messages};
+
+pub fn parse_cidr(s: &str) -> Result<IpNet, String> {
+    if let Ok(net) = s.parse::<IpNet>() {
+        return Ok(net);
+    }
+    s.parse::<IpAddr>().map(IpNet::from).map_err(|err| format!("{}", err))
+}
+
+// 拒绝列表优先;允许列表非空时只放行列表内的地址
+pub async fn ip_filter<B>(req: Request<B>, next: Next<B>) -> Response {
+    let args = ARGS.get().unwrap();
+    if args.allow_ip.is_empty() && args.deny_ip.is_empty() {
+        return next.run(req).await;
+    }
+    let ip = match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        None => { return next.run(req).await; }
+        Some(ConnectInfo(addr)) => { addr.ip().to_canonical() }
+    };
+    let denied = args.deny_ip.iter().any(|net| net.contains(&ip))
+        || (!args.allow_ip.is_empty() && !args.allow_ip.iter().any(|net| net.contains(&ip)));
+    if denied {
+        debug!("ip {} rejected", ip);
+        return Json(envelope(json!({
+            "message":messages::get("forbidden"),
+            "code":403u16,
+        }))).into_response();
+    }
+    next.run(req).await
+}
@@ -0,0 +1,18 @@
+use async_openai::types::Role;
+use tracing::debug;
+
+use crate::{CONFIG, get_role, UserInput};
+
+// 按最后一条用户消息检测语言,选择配置文件 [languages] 中对应的系统提示词;
+// 检测结果不可靠或未配置该语言时返回 None,使用默认系统提示词
+pub fn system_prompt(input: &UserInput) -> Option<&'static str> {
+    let content = input.contents.iter()
+        .rev()
+        .find(|content| get_role(content.role.clone()) == Role::User)?;
+    let info = whatlang::detect(&content.content)?;
+    debug!("detected language: {} (confidence {:.2})", info.lang().code(), info.confidence());
+    if !info.is_reliable() {
+        return None;
+    }
+    CONFIG.get().unwrap().languages.get(info.lang().code()).map(|prompt| prompt.as_str())
+}
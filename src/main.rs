@@ -1,47 +1,221 @@
+use std::collections::HashMap;
 use std::error::Error;
-use std::time::{Duration, Instant};
+use std::fs;
+use std::net::SocketAddr;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 use async_openai::{
     Client,
-    types::{ChatCompletionRequestMessageArgs, CreateChatCompletionRequestArgs, Role},
+    types::{ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs, Role},
 };
-use async_openai::config::OpenAIConfig;
-use async_openai::types::ChatCompletionRequestMessage;
-use axum::{Json, Router};
-use axum::routing::post;
-use clap::Parser;
-use serde::Deserialize;
+use async_openai::error::OpenAIError;
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionTool, CompletionUsage, CreateChatCompletionRequest, FinishReason, ServiceTier, Stop};
+use axum::{Extension, Json, Router};
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum_server::tls_rustls::RustlsConfig;
+use clap::{Parser, Subcommand, ValueEnum};
+use ipnet::IpNet;
+use parking_lot::RwLock;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize, Serializer};
 use serde_json::{json, Value};
 use tokio::signal;
-use tokio::sync::OnceCell;
-use tracing::{debug, error, Level, trace};
+use tokio::sync::{OnceCell, Semaphore};
+use opentelemetry::trace::TracerProvider as _;
+use tracing::{debug, error, info, Level, trace, warn};
+use tracing_subscriber::layer::SubscriberExt;
 
+use crate::audit::AuditLog;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::{Config, Example, RouteConfig};
+use crate::extract::ChatInput;
+use crate::hooks::RequestMiddleware;
+use crate::injection::BlockInjection;
+use crate::provider::{Provider, UpstreamConfig};
+use crate::request_id::{Peer, RequestId};
+use crate::retry_budget::RetryBudget;
+use crate::throttle::Throttle;
+use crate::tls::TlsVersion;
+
+mod admin;
+mod audit;
+mod batch;
+mod benchmark;
+mod body_guard;
+mod cancel;
+mod circuit_breaker;
+mod coalesce;
+mod compare;
+mod config;
+mod content_type;
+mod extract;
+mod hooks;
+mod idempotency;
+mod injection;
+mod ip_filter;
+mod language;
+mod markdown;
+mod messages;
+mod prompt_hash;
+mod provider;
+mod request_id;
+mod resume;
+mod schema;
+mod retry_budget;
+mod shutdown;
+mod status;
+mod stream;
+mod summarize;
+mod telemetry;
+mod throttle;
+mod tls;
+mod tokenizer;
+mod upstream;
+
+static ARGS: OnceCell<Args> = OnceCell::const_new();
+static CONFIG: OnceCell<Config> = OnceCell::const_new();
 static API_KEY: OnceCell<String> = OnceCell::const_new();
+static CIRCUIT_BREAKER: OnceCell<CircuitBreaker> = OnceCell::const_new();
+static HTTP_CLIENT: OnceCell<RwLock<reqwest::Client>> = OnceCell::const_new();
+static THROTTLE: OnceCell<Throttle> = OnceCell::const_new();
+static RETRY_BUDGET: OnceCell<RetryBudget> = OnceCell::const_new();
+static STREAM_PERMITS: OnceCell<Arc<Semaphore>> = OnceCell::const_new();
+static AUDIT_LOG: OnceCell<AuditLog> = OnceCell::const_new();
+static MIDDLEWARES: OnceCell<Vec<Box<dyn RequestMiddleware>>> = OnceCell::const_new();
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let collector = tracing_subscriber::fmt().with_max_level(Level::TRACE).with_env_filter("openai_api_server").finish();
+    let args = Args::parse();
+
+    let tracer_provider = match &args.otlp_endpoint {
+        None => None,
+        Some(endpoint) => Some(telemetry::init_tracer_provider(endpoint)?),
+    };
+    let otel_layer = tracer_provider.as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("openai_api_server")));
+    let collector = tracing_subscriber::fmt().with_max_level(Level::TRACE).with_env_filter("openai_api_server").finish()
+        .with(otel_layer);
     // let collector = tracing_subscriber::fmt().with_max_level(Level::TRACE).finish();
     tracing::subscriber::set_global_default(collector).expect("Unable to set a global collector");
 
     debug!("start");
-    let args = Args::parse();
+    if let Some(endpoint) = &args.otlp_endpoint {
+        debug!("otlp_endpoint: {}", endpoint);
+    }
     let port = args.port;
     debug!("port: {}", port);
 
-    match args.api_key {
-        None => {}
-        Some(key) => {
-            API_KEY.get_or_init(|| async {
-                debug!("api_key: {}", key);
-                key
-            }).await;
+    if let Some(Command::ValidateConfig { path }) = &args.command {
+        let path = path.as_ref().or(args.config.as_ref()).ok_or("no config file given")?;
+        let errors = config::check(path);
+        if errors.is_empty() {
+            println!("{}: ok", path);
+            return Ok(());
+        }
+        for err in errors {
+            eprintln!("{}: {}", path, err);
+        }
+        std::process::exit(1);
+    }
+
+    provider::check(args.provider, args.api_base.as_deref(), args.api_version.as_deref())?;
+
+    let config = match &args.config {
+        None => { Config::default() }
+        Some(path) => {
+            debug!("config: {}", path);
+            config::load(path)?
+        }
+    };
+    CONFIG.get_or_init(|| async { config }).await;
+    MIDDLEWARES.get_or_init(|| async { hooks::register(&CONFIG.get().unwrap().middleware) }).await;
+
+    // 服务端密钥: --api-key 优先,其次 --api-key-file(去掉末尾的空白和换行),最后读取环境变量 OPENAI_API_KEY;
+    // 日志中只记录来源,不记录密钥
+    let api_key = match (&args.api_key, &args.api_key_file) {
+        (Some(key), _) => { Some((key.clone(), "server")) }
+        (None, Some(path)) => {
+            let key = fs::read_to_string(path).map_err(|err| format!("--api-key-file {}: {}", path, err))?;
+            let key = key.trim_end().to_string();
+            if key.is_empty() {
+                return Err(format!("--api-key-file {}: file is empty", path).into());
+            }
+            Some((key, "file"))
         }
+        (None, None) => { std::env::var("OPENAI_API_KEY").ok().filter(|key| !key.is_empty()).map(|key| (key, "env")) }
+    };
+    if let Some((key, source)) = api_key {
+        API_KEY.get_or_init(|| async {
+            debug!("api_key source: {}", source);
+            key
+        }).await;
+    }
+
+    CIRCUIT_BREAKER.get_or_init(|| async {
+        CircuitBreaker::new(args.breaker_threshold, Duration::from_secs(args.breaker_cooldown), Duration::from_secs(args.timeout))
+    }).await;
+    RETRY_BUDGET.get_or_init(|| async { RetryBudget::new(args.retry_budget) }).await;
+    if let Some(path) = &args.audit_log {
+        let audit_log = AuditLog::open(path)?;
+        AUDIT_LOG.get_or_init(|| async { audit_log }).await;
+    }
+    // --max-streams 为 0 时不限制
+    STREAM_PERMITS.get_or_init(|| async {
+        let permits = if args.max_streams == 0 { Semaphore::MAX_PERMITS } else { args.max_streams };
+        Arc::new(Semaphore::new(permits))
+    }).await;
+    THROTTLE.get_or_init(|| async {
+        Throttle::new(args.throttle_threshold, Duration::from_secs(args.throttle_max_wait))
+    }).await;
+
+    for (name, _) in &args.upstream_header {
+        debug!("upstream_header: {}", name);
+    }
+    debug!("user_agent: {}", args.user_agent);
+    debug!("connect_timeout: {}s, read_timeout: {}s", args.connect_timeout, args.read_timeout);
+    let http_client = build_http_client(&args)?;
+    HTTP_CLIENT.get_or_init(|| async { RwLock::new(http_client) }).await;
+
+    ARGS.get_or_init(|| async { args }).await;
+    // 定期重建连接池,丢掉经过 NAT/负载均衡后可能已失效的长连接;进行中的请求持有旧的 Client,结束后旧连接池自然释放
+    if ARGS.get().unwrap().client_max_lifetime > 0 {
+        tokio::spawn(async {
+            let lifetime = Duration::from_secs(ARGS.get().unwrap().client_max_lifetime);
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + lifetime, lifetime);
+            loop {
+                interval.tick().await;
+                match build_http_client(ARGS.get().unwrap()) {
+                    Ok(client) => {
+                        *HTTP_CLIENT.get().unwrap().write() = client;
+                        debug!("http client rebuilt");
+                    }
+                    Err(err) => { warn!("Unable to rebuild http client: {}", err); }
+                }
+            }
+        });
+    }
+
+    if let Some(Command::Benchmark { requests, concurrency, model, prompt, max_tokens }) = &ARGS.get().unwrap().command {
+        benchmark::run(*requests, *concurrency, model.clone(), prompt.clone(), *max_tokens).await?;
+        return Ok(());
+    }
+
+    if ARGS.get().unwrap().warmup {
+        warmup().await;
     }
 
-    tokio::spawn(async move {
-        start_server(port).await.unwrap();
+    let args = ARGS.get().unwrap();
+    let tls_config = tls::server_config(args.tls_cert.as_deref(), args.tls_key.as_deref(), args.min_tls_version, &args.tls_ciphers)?;
+    let server = tokio::spawn(async move {
+        start_server(port, tls_config).await.unwrap();
     });
 
     match signal::ctrl_c().await {
@@ -52,21 +226,100 @@ async fn main() -> Result<(), Box<dyn Error>> {
             error!("Unable to listen for shutdown signal: {}", err);
         }
     }
+    // 停止接收新连接,等待进行中的请求结束;流式请求在超时后会收到 503 事件,这里多留 1 秒让事件发出去
+    shutdown::trigger();
+    let shutdown_timeout = Duration::from_secs(ARGS.get().unwrap().shutdown_timeout + 1);
+    if tokio::time::timeout(shutdown_timeout, server).await.is_err() {
+        warn!("graceful shutdown timed out after {:?}", shutdown_timeout);
+    }
+    if let Some(provider) = tracer_provider {
+        if let Err(err) = provider.shutdown() {
+            error!("Unable to shut down tracer provider: {}", err);
+        }
+    }
     Ok(())
 }
 
-async fn start_server(port: u16) -> Result<()> {
-    let app = Router::new().route("/chat", post(openai_handler));
+// 启动时请求一次 models 接口,提前建立到 OpenAI 的连接
+async fn warmup() {
+    let api_key = match resolve_api_key("") {
+        None => {
+            debug!("warmup skipped: api_key is empty");
+            return;
+        }
+        Some(key) => { key }
+    };
+    let start_time = Instant::now();
+    match build_client(api_key, "").models().list().await {
+        Ok(_) => {
+            debug!("warmup done in {:?}ms", time_diff(start_time, Instant::now()).as_millis());
+        }
+        Err(err) => {
+            warn!("warmup failed: {}", err);
+        }
+    }
+}
+
+async fn start_server(port: u16, tls_config: Option<RustlsConfig>) -> Result<()> {
+    let app = router();
 
-    axum::Server::try_bind(&format!("0.0.0.0:{}", port).parse()?)?
-        .serve(app.into_make_service())
+    let addr: SocketAddr = format!("0.0.0.0:{}", port).parse()?;
+    if let Some(tls_config) = tls_config {
+        let handle = axum_server::Handle::new();
+        let shutdown_handle = handle.clone();
+        tokio::spawn(async move {
+            shutdown::signal().await;
+            shutdown_handle.graceful_shutdown(None);
+        });
+        axum_server::bind_rustls(addr, tls_config)
+            .handle(handle)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await?;
+        return Ok(());
+    }
+    axum::Server::try_bind(&addr)?
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown::signal())
         .await?;
     Ok(())
 }
 
+fn router() -> Router {
+    let mut app = Router::new()
+        .route("/chat", post(openai_handler))
+        .route("/chat/stream", post(stream::stream_handler))
+        .route("/chat/stream.txt", post(stream::text_handler))
+        .route("/chat/cancel/:request_id", post(cancel_handler))
+        .route("/chat/compare", post(compare::compare_handler))
+        .route("/chat/batch", post(batch::batch_handler))
+        .route("/summarize", post(summarize::summarize_handler))
+        .route("/status", get(status::status_handler));
+    for (name, route) in &CONFIG.get().unwrap().routes {
+        debug!("route: /chat/{} -> {}", name, route.model);
+        app = app.route(&format!("/chat/{}", name), post(move |Extension(request_id): Extension<RequestId>, Extension(peer): Extension<Peer>, Query(params): Query<ChatParams>, headers: axum::http::HeaderMap, ChatInput(input): ChatInput| async move {
+            let text = wants_text(&params, &headers);
+            let minimal = wants_minimal(&params, &headers);
+            let ctx = ChatContext { route: Some(route), request_id, peer, params, headers, schema_attempt: 0, empty_retried: false };
+            respond(idempotent_chat(input, ctx).await, text, minimal)
+        }));
+    }
+    let admin = Router::new()
+        .route("/admin/config", get(admin::config_handler))
+        .route("/admin/prompt-hashes", get(prompt_hash::stats_handler))
+        .route("/ping", get(admin::ping_handler))
+        .route_layer(middleware::from_fn(admin::admin_auth));
+    app.merge(admin)
+        .layer(middleware::from_fn(status::load_headers))
+        .layer(middleware::from_fn(content_type::content_type))
+        .layer(middleware::from_fn(body_guard::body_guard))
+        .layer(middleware::from_fn(request_id::request_id))
+        .layer(middleware::from_fn(ip_filter::ip_filter))
+}
+
 
 // {
 // "api_key":"sk-xxxxxxxxx",
+// "model":"gpt-3.5-turbo",
 // "max_tokens":1024,
 // "contents":[
 // {
@@ -75,19 +328,52 @@ async fn start_server(port: u16) -> Result<()> {
 // }
 // ]
 // }
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 #[allow(dead_code)]
 struct Content {
     pub role: String,
     pub content: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 #[allow(dead_code)]
 struct UserInput {
     #[serde(default = "default_api_key")]
     pub api_key: String,
-    pub max_tokens: u16,
+    #[serde(default)]
+    pub model: String,
+    pub max_tokens: Option<u32>,
+    pub max_completion_tokens: Option<u32>,
+    pub temperature: Option<f32>,
+    pub stop: Option<Vec<String>>,
+    pub tools: Option<Vec<ChatCompletionTool>>,
+    pub parallel_tool_calls: Option<bool>,
+    pub logprobs: Option<bool>,
+    pub top_logprobs: Option<u8>,
+    pub n: Option<u8>,
+    // 除主回答外再返回几种不同的说法,内部按 n = variations + 1 请求
+    pub variations: Option<u8>,
+    // 输出须符合的 JSON Schema
+    pub json_schema: Option<Value>,
+    // 业务自定义的标签,不发给 OpenAI,只记录在审计日志和 tracing span 中
+    pub metadata: Option<HashMap<String, String>>,
+    pub service_tier: Option<String>,
+    pub template: Option<String>,
+    pub persona: Option<String>,
+    // 客户端的系统提示词,代替 contents 中的 system 消息
+    pub system: Option<String>,
+    #[serde(default)]
+    pub disable_system_prompt: bool,
+    // 去掉回复中的 markdown,未设置时使用 --strip-markdown
+    pub plain: Option<bool>,
+    // 客户端发出请求的时间,unix 毫秒
+    pub ts: Option<u64>,
+    pub timeout_secs: Option<u64>,
+    // 覆盖 --max-retries,不超过 --max-retries-limit
+    pub max_retries: Option<u8>,
+    // 只对 /chat/stream 有意义
+    #[serde(default)]
+    pub stream: bool,
     pub contents: Vec<Content>,
 }
 
@@ -102,85 +388,953 @@ fn default_api_key() -> String {
     String::new()
 }
 
-async fn openai_handler(Json(input): Json<UserInput>) -> Json<Value> {
-    let max_tokens = input.max_tokens;
-    let contents = input.contents;
+fn default_model() -> String {
+    "gpt-3.5-turbo".to_string()
+}
 
+#[derive(Deserialize, Clone, Debug, Default)]
+struct ChatParams {
+    #[serde(default)]
+    raw: bool,
+    format: Option<String>,
+    #[serde(default)]
+    echo_params: bool,
+    #[serde(default)]
+    minimal: bool,
+    #[serde(default)]
+    return_conversation: bool,
+}
 
-    let api_key: String;
-    if input.api_key.is_empty() {
-        match API_KEY.get() {
-            None => {
-                return Json(json!({
-                "message":"api_key is empty",
-                "code":500u16,
-            }));
+#[derive(Clone)]
+struct ChatContext {
+    route: Option<&'static RouteConfig>,
+    request_id: RequestId,
+    peer: Peer,
+    params: ChatParams,
+    headers: axum::http::HeaderMap,
+    // 输出不符合 json_schema 时已重试的次数
+    schema_attempt: u32,
+    // --retry-empty-choices 时是否已因空 choices 重试过
+    empty_retried: bool,
+}
+
+async fn openai_handler(Extension(request_id): Extension<RequestId>, Extension(peer): Extension<Peer>, Query(params): Query<ChatParams>, headers: axum::http::HeaderMap, ChatInput(input): ChatInput) -> Response {
+    let text = wants_text(&params, &headers);
+    let minimal = wants_minimal(&params, &headers);
+    let ctx = ChatContext { route: None, request_id, peer, params, headers, schema_attempt: 0, empty_retried: false };
+    respond(idempotent_chat(input, ctx).await, text, minimal)
+}
+
+// 带 Idempotency-Key 的请求在 --idempotency-ttl 秒内重复时直接返回之前的响应
+async fn idempotent_chat(input: UserInput, ctx: ChatContext) -> Value {
+    let ttl = ARGS.get().unwrap().idempotency_ttl;
+    let key = ctx.headers.get("idempotency-key").and_then(|value| value.to_str().ok());
+    match key {
+        Some(key) if ttl > 0 => {
+            // 与取消、断线重连一样按调用方区分(api_key 的哈希或客户端地址),避免不同客户端用了相同的 key
+            let key = format!("{}:{}", request_id::owner(&input.api_key, ctx.peer), key);
+            let fingerprint = idempotency::fingerprint(&input);
+            idempotency::run(key, fingerprint, Duration::from_secs(ttl), hooks::run(MIDDLEWARES.get().unwrap(), input, ctx)).await
+        }
+        _ => { hooks::run(MIDDLEWARES.get().unwrap(), input, ctx).await }
+    }
+}
+
+// ?format=text 或 Accept: text/plain 时只返回内容本身
+fn wants_text(params: &ChatParams, headers: &axum::http::HeaderMap) -> bool {
+    if let Some(format) = &params.format {
+        return format == "text";
+    }
+    headers.get(axum::http::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with("text/plain"))
+        .unwrap_or(false)
+}
+
+// ?minimal=true 或 X-Minimal: true 时只返回 code 和 message,省掉 usage、model 等附加字段
+fn wants_minimal(params: &ChatParams, headers: &axum::http::HeaderMap) -> bool {
+    params.minimal || headers.get("x-minimal").map(|value| value == "true").unwrap_or(false)
+}
+
+// 纯文本模式下 code 作为 HTTP 状态码,body 为回答或错误信息
+fn respond(body: Value, text: bool, minimal: bool) -> Response {
+    let body = if minimal {
+        json!({"message":body["message"],"code":body["code"]})
+    } else {
+        body
+    };
+    if !text {
+        return Json(envelope(body)).into_response();
+    }
+    let status = body["code"].as_u64()
+        .and_then(|code| StatusCode::from_u16(code as u16).ok())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let message = body["message"].as_str().unwrap_or_default().to_string();
+    (status, [(axum::http::header::CONTENT_TYPE, "text/plain; charset=utf-8")], message).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+struct CancelInput {
+    #[serde(default)]
+    api_key: String,
+}
+
+// 请求带了 api_key 时须在请求体中带上同样的 api_key: {"api_key":"sk-xxx"},否则须来自同一个客户端地址
+async fn cancel_handler(Extension(peer): Extension<Peer>, Path(request_id): Path<String>, body: Option<Json<CancelInput>>) -> Json<Value> {
+    let api_key = body.map(|Json(body)| body.api_key).unwrap_or_default();
+    if cancel::cancel(&request_id, &request_id::owner(&api_key, peer)) {
+        Json(envelope(json!({
+            "message":messages::get("cancelled"),
+            "code":200u16,
+        })))
+    } else {
+        Json(envelope(json!({
+            "message":messages::get("request_not_found"),
+            "code":404u16,
+        })))
+    }
+}
+
+// 统一响应格式: 成功 {"code":200,"data":{"message":..},"error":null}
+// 失败 {"code":..,"data":null,"error":{"message":..}}; --legacy-response 时保持旧格式 {"code":..,"message":..}
+fn envelope(body: Value) -> Value {
+    if ARGS.get().unwrap().legacy_response {
+        return body;
+    }
+    let mut map = match body {
+        Value::Object(map) => { map }
+        other => { return other; }
+    };
+    let code = map.remove("code").unwrap_or(Value::Null);
+    if code == json!(200u16) {
+        json!({"code":code,"data":map,"error":null})
+    } else {
+        json!({"code":code,"data":null,"error":map})
+    }
+}
+
+// 未指定 model 时,使用路由别名绑定的模型,否则使用默认模型
+// 未指定的参数使用配置文件中该模型的默认值
+fn apply_defaults(input: &mut UserInput, route: Option<&RouteConfig>) {
+    if input.model.is_empty() {
+        input.model = match route {
+            None => { default_model() }
+            Some(route) => { route.model.clone() }
+        };
+    }
+    if let Some(defaults) = CONFIG.get().unwrap().models.get(&input.model) {
+        input.temperature = input.temperature.or(defaults.temperature);
+        if input.max_tokens.is_none() && input.max_completion_tokens.is_none() {
+            input.max_tokens = defaults.max_tokens;
+        }
+    }
+}
+
+// 路由别名未配置 examples 时使用全局的
+fn examples(route: Option<&RouteConfig>) -> &[Example] {
+    match route.and_then(|route| route.examples.as_ref()) {
+        Some(examples) => { examples }
+        None => { &CONFIG.get().unwrap().examples }
+    }
+}
+
+// 按路由的 max_tokens_limit(未配置时用 --max-tokens-limit)限制 max_tokens,
+// 超出时按 --max-tokens-policy 截到上限或拒绝;请求未指定时使用上限
+fn limit_max_tokens(input: &mut UserInput, route: Option<&RouteConfig>) -> Result<(), String> {
+    let args = ARGS.get().unwrap();
+    // 超过模型上下文长度的值不可能被满足,直接截到上下文长度
+    if let Some(context_size) = tokenizer::context_size(&input.model) {
+        for max_tokens in [&mut input.max_tokens, &mut input.max_completion_tokens].into_iter().flatten() {
+            if *max_tokens as usize > context_size {
+                debug!("max_tokens {} exceeds context size {} of {}, clamped", max_tokens, context_size, input.model);
+                *max_tokens = context_size as u32;
             }
-            Some(key) => {
-                api_key = key.to_string()
+        }
+    }
+    let Some(limit) = route.and_then(|route| route.max_tokens_limit).or(args.max_tokens_limit) else {
+        return Ok(());
+    };
+    if input.max_tokens.is_none() && input.max_completion_tokens.is_none() {
+        input.max_tokens = Some(limit);
+        return Ok(());
+    }
+    for max_tokens in [&mut input.max_tokens, &mut input.max_completion_tokens].into_iter().flatten() {
+        if *max_tokens > limit {
+            if args.max_tokens_policy == LimitPolicy::Reject {
+                return Err(format!("max_tokens must not exceed {}, got {}", limit, max_tokens));
             }
+            *max_tokens = limit;
         }
-    } else {
-        api_key = input.api_key;
     }
+    Ok(())
+}
 
-    let mut request_messages: Vec<ChatCompletionRequestMessage> = Vec::new();
-    for content in contents {
-        let request_message = ChatCompletionRequestMessageArgs::default()
-            .role(get_role(content.role))
-            .content(content.content)
-            .build().unwrap();
-        request_messages.push(request_message);
+// 未指定 n 时使用 --default-n;超过 --max-n 时按 --n-policy 截断或拒绝,返回是否被截断
+fn limit_n(input: &mut UserInput) -> Result<bool, String> {
+    let args = ARGS.get().unwrap();
+    // variations 只受 --max-variations 限制
+    if let Some(variations) = input.variations {
+        if variations > args.max_variations {
+            return Err(format!("variations must not exceed {}, got {}", args.max_variations, variations));
+        }
+        return Ok(false);
+    }
+    if input.n.is_none() {
+        input.n = args.default_n;
     }
+    match (input.n, args.max_n) {
+        (Some(n), Some(max_n)) if n > max_n => {
+            if args.n_policy == LimitPolicy::Reject {
+                return Err(format!("n must not exceed {}, got {}", max_n, n));
+            }
+            input.n = Some(max_n);
+            Ok(true)
+        }
+        _ => { Ok(false) }
+    }
+}
 
-    let cfg = OpenAIConfig::default().with_api_key(api_key);
-    let client = Client::with_config(cfg);
+async fn chat(mut input: UserInput, ctx: ChatContext) -> Json<Value> {
+    let _active = status::track();
+    // X-Debug: true 时在日志中打印本次请求和响应的完整内容
+    let debug_request = ctx.headers.get("x-debug").map(|value| value == "true").unwrap_or(false);
+    // 原始响应和调试日志只对管理员开放
+    if (ctx.params.raw || debug_request) && !admin::authorized(&ctx.headers) {
+        return Json(audit::log(&ctx.request_id.0, &input, json!({
+            "message":messages::get("unauthorized"),
+            "code":401u16,
+        })));
+    }
+    if input.stream {
+        return Json(audit::log(&ctx.request_id.0, &input, json!({
+            "message":messages::get("stream_not_supported"),
+            "code":400u16,
+        })));
+    }
+    if let Some(phrase) = injection::scan(&input) {
+        return Json(audit::log(&ctx.request_id.0, &input, json!({
+            "message":messages::get("prompt_injection"),
+            "code":403u16,
+            "phrase":phrase,
+        })));
+    }
+    apply_defaults(&mut input, ctx.route);
+    if let Err(err) = limit_max_tokens(&mut input, ctx.route) {
+        return Json(audit::log(&ctx.request_id.0, &input, json!({
+            "message":messages::get("invalid_request"),
+            "code":400u16,
+            "errors":[err],
+        })));
+    }
+    let n_clamped = match limit_n(&mut input) {
+        Ok(s) => { s }
+        Err(err) => {
+            return Json(audit::log(&ctx.request_id.0, &input, json!({
+                "message":messages::get("invalid_request"),
+                "code":400u16,
+                "errors":[err],
+            })));
+        }
+    };
+    if !model_allowed(&input.model) {
+        return Json(audit::log(&ctx.request_id.0, &input, json!({
+            "message":format!("model {} is not allowed",input.model),
+            "code":403u16,
+        })));
+    }
+    let api_key = match resolve_api_key(&input.api_key) {
+        None => {
+            return Json(audit::log(&ctx.request_id.0, &input, json!({
+                "message":messages::get("api_key_empty"),
+                "code":500u16,
+            })));
+        }
+        Some(key) => { key }
+    };
+    let config = build_config(api_key, &input.model).forward(&ctx.headers);
 
-    let request = match CreateChatCompletionRequestArgs::default()
-        .max_tokens(max_tokens)
-        .model("gpt-3.5-turbo")
-        .messages(request_messages)
-        .build() {
+    let request = match build_chat_request(&input, examples(ctx.route)) {
         Ok(s) => { s }
+        Err(ChatRequestError::Invalid(errors)) => {
+            return Json(audit::log(&ctx.request_id.0, &input, json!({
+                "message":messages::get("invalid_request"),
+                "code":400u16,
+                "errors":errors,
+            })));
+        }
         Err(err) => {
-            return Json(json!({
+            return Json(audit::log(&ctx.request_id.0, &input, json!({
                 "message":format!("{}",err),
                 "code":500u16,
-            }));
+            })));
+        }
+    };
+    // 按 --log-sample-rate 抽样记录完整的请求和响应;出错时总是记录错误,请求内容只在抽中时记录
+    let log_details = debug_request || ctx.request_id.sampled(ARGS.get().unwrap().log_sample_rate);
+    if log_details {
+        info!("debug request: {}", serde_json::to_string(&request).unwrap_or_default());
+    }
+    let log_failed = |err: &dyn Display| {
+        if log_details {
+            warn!("request failed: {}, request: {}", err, serde_json::to_string(&request).unwrap_or_default());
+        } else {
+            warn!("request failed: {}", err);
         }
     };
+    let breaker = CIRCUIT_BREAKER.get().unwrap();
+    if !breaker.allow() {
+        return Json(audit::log(&ctx.request_id.0, &input, json!({
+            "message":messages::get("upstream_unavailable"),
+            "code":503u16,
+        })));
+    }
     let start_time = Instant::now();
-    let response = match client.chat().create(request).await {
-        Ok(s) => { s }
-        Err(err) => {
+    let throttle = THROTTLE.get().unwrap();
+    throttle.wait().await;
+    let timeout = request_timeout(&input);
+    let mut cancellation = cancel::register(&ctx.request_id.0, request_id::owner(&input.api_key, ctx.peer));
+    // --partial-on-timeout 时改用流式请求,超时后返回已生成的部分;tools、logprobs 和 n > 1 仍走非流式
+    let partial_on_timeout = ARGS.get().unwrap().partial_on_timeout && request.tools.is_none() && request.logprobs != Some(true) && request.n.unwrap_or(1) == 1;
+    let mut partial = String::new();
+    let upstream_call = async {
+        if partial_on_timeout {
+            upstream::create_chat_completion_streamed(&config, &request, &input, &mut partial).await
+        } else {
+            upstream::create_chat_completion_with_retry(&config, &request, &input).await
+        }
+    };
+    let result = tokio::select! {
+        result = tokio::time::timeout(timeout, upstream_call) => { result }
+        Ok(()) = &mut cancellation.receiver => {
+            debug!("request cancelled");
+            return Json(audit::log(&ctx.request_id.0, &input, json!({
+                "message":messages::get("request_cancelled"),
+                "code":499u16,
+            })));
+        }
+    };
+    let response = match result {
+        Ok(Ok((s, headers))) => {
+            throttle.update(&headers);
+            s
+        }
+        Ok(Err(err)) if upstream::is_context_length_exceeded(&err) => {
+            let max_context = upstream::max_context(&err);
+            // --auto-truncate-context 时丢掉最早的消息后重试,直到放得下或无法再删
+            if let Some(max_context) = max_context.filter(|_| ARGS.get().unwrap().auto_truncate_context) {
+                if trim_contents(&mut input, max_context) {
+                    info!("context length exceeded, retrying with {} messages", input.contents.len());
+                    drop(cancellation);
+                    let Json(mut body) = Box::pin(chat(input, ctx)).await;
+                    body["context_truncated"] = json!(true);
+                    return Json(body);
+                }
+            }
+            log_failed(&err);
+            audit::record(&ctx.request_id.0, &input, 400, None, None);
+            return Json(json!({
+                "message":messages::get("context_length_exceeded"),
+                "code":400u16,
+                "max_context":max_context,
+            }));
+        }
+        Ok(Err(err)) => {
+            log_failed(&err);
+            audit::record(&ctx.request_id.0, &input, 500, None, None);
             return Json(json!({
                 "message":format!("{}",err),
                 "code":500u16,
             }));
         }
+        Err(err) => {
+            breaker.on_failure();
+            log_failed(&err);
+            audit::record(&ctx.request_id.0, &input, 504, None, (!partial.is_empty()).then_some(partial.as_str()));
+            if !partial.is_empty() {
+                debug!("upstream timeout after {}s, returning {} chars", timeout.as_secs(), partial.len());
+                return Json(json!({
+                    "message":partial,
+                    "code":200u16,
+                    "key_source":key_source(&input.api_key),
+                    "timed_out":true,
+                    "partial":true,
+                }));
+            }
+            return Json(json!({
+                "message":format!("upstream timeout after {}s",timeout.as_secs()),
+                "code":504u16,
+            }));
+        }
     };
+    let content = response.choices.first().and_then(|choice| choice.message.content.as_deref());
+    audit::record(&ctx.request_id.0, &input, 200, response.usage.as_ref(), content);
+    prompt_hash::record(&input, response.usage.as_ref());
+    if log_details {
+        info!("debug response: {}", serde_json::to_string(&response).unwrap_or_default());
+    }
+    if ctx.params.raw {
+        return Json(json!({
+            "code":200u16,
+            "raw":response,
+        }));
+    }
     if response.choices.is_empty() {
+        warn!("upstream returned no choices");
+        // 偶尔是暂时的,按 --retry-empty-choices 重新请求一次
+        if ARGS.get().unwrap().retry_empty_choices && !ctx.empty_retried {
+            info!("retrying after empty choices");
+            drop(cancellation);
+            let mut ctx = ctx;
+            ctx.empty_retried = true;
+            return Box::pin(chat(input, ctx)).await;
+        }
         return Json(json!({
-            "message":"no choices",
+            "message":messages::get("no_choices"),
             "code":500u16,
         }));
     }
-    let resp = match &response.choices[0].message.content {
+    let message = &response.choices[0].message;
+    if let Some(tool_calls) = &message.tool_calls {
+        return Json(json!({
+            "message":message.content.clone().unwrap_or_default(),
+            "code":200u16,
+            "tool_calls":tool_calls,
+        }));
+    }
+    let resp = match &message.content {
         None => {
             return Json(json!({
-                "message":"no content",
+                "message":messages::get("no_content"),
                 "code":500u16,
             }));
         }
-        Some(s) => { s }
+        Some(s) => { s.clone() }
     };
     let end_time = Instant::now();
     let duration = time_diff(start_time, end_time);
     debug!("duration: {:?}", duration.as_millis());
-    Json(json!({
+
+    let args = ARGS.get().unwrap();
+    // 便宜模型的回答被截断或命中 --escalate-on 时,换 --escalate-model 重新请求一次
+    if let Some(escalate_model) = &args.escalate_model {
+        if input.model != *escalate_model && should_escalate(&resp, response.choices[0].finish_reason) {
+            info!("escalating from {} to {}", input.model, escalate_model);
+            let from = std::mem::replace(&mut input.model, escalate_model.clone());
+            let Json(mut body) = Box::pin(chat(input, ctx)).await;
+            body["escalated_from"] = json!(from);
+            return Json(body);
+        }
+    }
+    // 带 json_schema 时校验输出,不符合时按 --schema-retries 重新请求
+    let mut output = None;
+    if let Some(json_schema) = &input.json_schema {
+        match schema::validate(json_schema, &resp) {
+            Ok(value) => { output = Some(value); }
+            Err(errors) if ctx.schema_attempt < args.schema_retries => {
+                info!("response does not match json_schema, retry {}/{}: {:?}", ctx.schema_attempt + 1, args.schema_retries, errors);
+                drop(cancellation);
+                let mut ctx = ctx;
+                ctx.schema_attempt += 1;
+                return Box::pin(chat(input, ctx)).await;
+            }
+            Err(errors) => {
+                return Json(json!({
+                    "message":messages::get("schema_mismatch"),
+                    "code":422u16,
+                    "errors":errors,
+                }));
+            }
+        }
+    }
+    // 清理控制字符、去掉 markdown 并按上限截断,返回是否被截断
+    let postprocess = |resp: String| {
+        let mut resp = if args.sanitize_output { sanitize(&resp) } else { resp };
+        if input.plain.unwrap_or(args.strip_markdown) {
+            resp = markdown::strip(&resp);
+        }
+        let mut truncated = false;
+        if let Some(max_tokens) = args.max_response_tokens {
+            if let Some(s) = tokenizer::truncate_tokens(&input.model, &resp, max_tokens) {
+                debug!("response truncated to {} tokens", max_tokens);
+                resp = s;
+                truncated = true;
+            }
+        }
+        if let Some(max_chars) = args.max_response_chars {
+            if let Some(s) = truncate_chars(&resp, max_chars) {
+                debug!("response truncated to {} chars", max_chars);
+                resp = s;
+                truncated = true;
+            }
+        }
+        (resp, truncated)
+    };
+    let (resp, truncated) = postprocess(resp);
+    let mut body = json!({
        "message":resp,
        "code":200u16,
-   }))
+       "key_source":key_source(&input.api_key),
+    });
+    if truncated {
+        body["truncated"] = json!(true);
+    }
+    if let Some(cost) = cost_usd(&input.model, response.usage.as_ref()) {
+        body["cost_usd"] = json!(cost);
+    }
+    if let Some(output) = output {
+        body["json"] = output;
+    }
+    if args.escalate_model.is_some() {
+        body["model"] = json!(input.model);
+    }
+    // n > 1 时 message 为第一个回答,choices 为全部回答;使用 variations 时其余回答放在 alternatives 中
+    if input.variations.is_some() {
+        body["alternatives"] = response.choices.iter()
+            .skip(1)
+            .map(|choice| postprocess(choice.message.content.clone().unwrap_or_default()).0)
+            .collect();
+    } else if response.choices.len() > 1 {
+        body["choices"] = response.choices.iter()
+            .map(|choice| postprocess(choice.message.content.clone().unwrap_or_default()).0)
+            .collect();
+    }
+    if n_clamped {
+        body["n"] = json!(input.n);
+        body["n_clamped"] = json!(true);
+    }
+    // 请求的 contents 加上本次回答,客户端下一轮可以直接在后面追加用户消息再发送
+    if ctx.params.return_conversation {
+        let mut contents = input.contents.clone();
+        contents.push(Content { role: "assistant".to_string(), content: resp.clone() });
+        body["contents"] = json!(contents);
+    }
+    // 实际发给 OpenAI 的参数,不含消息内容
+    if ctx.params.echo_params {
+        let mut params = json!(request);
+        if let Some(params) = params.as_object_mut() {
+            params.remove("messages");
+        }
+        body["params"] = params;
+    }
+    if let Some(logprobs) = &response.choices[0].logprobs {
+        body["logprobs"] = json!(logprobs);
+    }
+    if let Some(service_tier) = &response.service_tier {
+        body["service_tier"] = json!(service_tier);
+    }
+    Json(body)
+}
+
+// 按本地 tokenizer 估算,从最早的非 system 消息开始删除(保留最后一条),
+// 直到消息加上 max_tokens 放得进 max_context;没有删除任何消息时返回 false
+fn trim_contents(input: &mut UserInput, max_context: usize) -> bool {
+    // 每条消息的格式开销约 4 个 token
+    let cost = |content: &Content| tokenizer::count_tokens(&input.model, &content.content) + 4;
+    let budget = max_context.saturating_sub(input.max_tokens.or(input.max_completion_tokens).unwrap_or(0) as usize);
+    let mut total: usize = input.contents.iter().map(cost).sum();
+    let mut removed = Vec::new();
+    for (index, content) in input.contents.iter().enumerate() {
+        if total <= budget || index + 1 == input.contents.len() {
+            break;
+        }
+        if get_role(content.role.clone()) != Role::System {
+            total -= cost(content);
+            removed.push(index);
+        }
+    }
+    let mut index = 0;
+    input.contents.retain(|_| {
+        index += 1;
+        !removed.contains(&(index - 1))
+    });
+    !removed.is_empty()
+}
+
+fn should_escalate(resp: &str, finish_reason: Option<FinishReason>) -> bool {
+    if finish_reason == Some(FinishReason::Length) {
+        return true;
+    }
+    let resp = resp.to_lowercase();
+    ARGS.get().unwrap().escalate_on.iter().any(|pattern| resp.contains(&pattern.to_lowercase()))
+}
+
+fn resolve_api_key(api_key: &str) -> Option<String> {
+    if api_key.is_empty() {
+        API_KEY.get().map(|key| key.to_string())
+    } else {
+        Some(api_key.to_string())
+    }
+}
+
+// 实际使用的密钥来源,用于排查多密钥配置的问题
+fn key_source(api_key: &str) -> &'static str {
+    if !api_key.is_empty() {
+        "request"
+    } else if ARGS.get().unwrap().api_key.is_some() {
+        "server"
+    } else if ARGS.get().unwrap().api_key_file.is_some() {
+        "file"
+    } else {
+        "env"
+    }
+}
+
+// 按配置文件 [prices] 计算本次请求的费用,没有配置该模型的价格或没有 usage 时返回 None
+fn cost_usd(model: &str, usage: Option<&CompletionUsage>) -> Option<f64> {
+    let price = CONFIG.get().unwrap().prices.get(model)?;
+    usage.map(|usage| price.cost(usage))
+}
+
+fn build_config(api_key: String, model: &str) -> UpstreamConfig {
+    UpstreamConfig::new(api_key, model)
+}
+
+fn build_client(api_key: String, model: &str) -> Client<UpstreamConfig> {
+    Client::with_config(build_config(api_key, model)).with_http_client(http_client())
+}
+
+// 当前的共享连接池,--client-max-lifetime 时会被定期替换
+fn http_client() -> reqwest::Client {
+    HTTP_CLIENT.get().unwrap().read().clone()
+}
+
+fn build_http_client(args: &Args) -> reqwest::Result<reqwest::Client> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &args.upstream_header {
+        headers.append(name.clone(), value.clone());
+    }
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(args.connect_timeout))
+        .read_timeout(Duration::from_secs(args.read_timeout))
+        .user_agent(args.user_agent.as_str())
+        .default_headers(headers)
+        .build()
+}
+
+#[derive(Debug)]
+enum ChatRequestError {
+    Invalid(Vec<String>),
+    Build(OpenAIError),
+}
+
+impl Display for ChatRequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatRequestError::Invalid(errors) => { write!(f, "invalid request: {}", errors.join("; ")) }
+            ChatRequestError::Build(err) => { write!(f, "{}", err) }
+        }
+    }
+}
+
+impl Error for ChatRequestError {}
+
+impl From<OpenAIError> for ChatRequestError {
+    fn from(err: OpenAIError) -> Self {
+        ChatRequestError::Build(err)
+    }
+}
+
+// metadata 的上限与 OpenAI 的 metadata 参数相同
+const MAX_METADATA_ENTRIES: usize = 16;
+const MAX_METADATA_KEY_CHARS: usize = 64;
+const MAX_METADATA_VALUE_CHARS: usize = 512;
+
+fn validate_input(input: &UserInput) -> Vec<String> {
+    let mut errors = Vec::new();
+    if input.contents.is_empty() {
+        errors.push("contents must not be empty".to_string());
+    }
+    if input.model.is_empty() {
+        errors.push("model must not be empty".to_string());
+    }
+    if let Some(temperature) = input.temperature {
+        if !(0.0..=2.0).contains(&temperature) {
+            errors.push(format!("temperature must be between 0 and 2, got {}", temperature));
+        }
+    }
+    if let Some(stop) = stop_sequences(input) {
+        if stop.len() > MAX_STOP_SEQUENCES && ARGS.get().unwrap().on_excess_stop == LimitPolicy::Reject {
+            errors.push(format!("stop allows at most {} sequences, got {}", MAX_STOP_SEQUENCES, stop.len()));
+        }
+    }
+    if input.max_tokens == Some(0) {
+        errors.push("max_tokens must be positive".to_string());
+    }
+    if input.max_completion_tokens == Some(0) {
+        errors.push("max_completion_tokens must be positive".to_string());
+    }
+    if input.n == Some(0) {
+        errors.push("n must be at least 1".to_string());
+    }
+    if input.system.as_deref().is_some_and(|system| system.trim().is_empty()) {
+        errors.push("system must not be empty".to_string());
+    }
+    if input.variations == Some(0) {
+        errors.push("variations must be at least 1".to_string());
+    }
+    if input.variations.is_some() && input.n.is_some() {
+        errors.push("variations cannot be combined with n".to_string());
+    }
+    if let Some(Err(err)) = input.json_schema.as_ref().map(schema::check) {
+        errors.push(err);
+    }
+    if let Some(metadata) = &input.metadata {
+        if metadata.len() > MAX_METADATA_ENTRIES {
+            errors.push(format!("metadata must not have more than {} entries, got {}", MAX_METADATA_ENTRIES, metadata.len()));
+        }
+        for (key, value) in metadata {
+            if key.chars().count() > MAX_METADATA_KEY_CHARS {
+                errors.push(format!("metadata key {} is longer than {} characters", key, MAX_METADATA_KEY_CHARS));
+            }
+            if value.chars().count() > MAX_METADATA_VALUE_CHARS {
+                errors.push(format!("metadata {} is longer than {} characters", key, MAX_METADATA_VALUE_CHARS));
+            }
+        }
+    }
+    if let Some(top_logprobs) = input.top_logprobs {
+        if top_logprobs > 20 {
+            errors.push(format!("top_logprobs must be between 0 and 20, got {}", top_logprobs));
+        }
+        if input.logprobs != Some(true) {
+            errors.push("top_logprobs requires logprobs to be true".to_string());
+        }
+    }
+    if let (Some(ts), Some(max_age)) = (input.ts, ARGS.get().unwrap().max_request_age) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let age = now.saturating_sub(ts);
+        if age > max_age.saturating_mul(1000) {
+            errors.push(format!("request is too old: sent {}ms ago, max age is {}s", age, max_age));
+        }
+    }
+    if let Some(template) = &input.template {
+        if !CONFIG.get().unwrap().templates.contains_key(template) {
+            errors.push(format!("template {} is not defined", template));
+        }
+    }
+    if let Some(persona) = &input.persona {
+        if !CONFIG.get().unwrap().personas.contains_key(persona) {
+            errors.push(format!("persona {} is not defined", persona));
+        }
+    }
+    if let Some(service_tier) = &input.service_tier {
+        if parse_service_tier(service_tier).is_none() {
+            errors.push(format!("service_tier must be auto or default, got {}", service_tier));
+        }
+    }
+    errors
+}
+
+#[allow(deprecated)]
+fn build_chat_request(input: &UserInput, examples: &[Example]) -> Result<CreateChatCompletionRequest, ChatRequestError> {
+    let errors = validate_input(input);
+    if !errors.is_empty() {
+        return Err(ChatRequestError::Invalid(errors));
+    }
+
+    let args = ARGS.get().unwrap();
+    let mut request_messages: Vec<ChatCompletionRequestMessage> = Vec::new();
+    // 模型不知道当前日期,按 --timezone 把服务器时间加在系统提示词前面
+    let datetime = args.inject_datetime
+        .then(|| format!("Current date: {}", chrono::Utc::now().with_timezone(&args.timezone).to_rfc3339()));
+    let persona = input.persona.as_ref().and_then(|name| CONFIG.get().unwrap().personas.get(name));
+    let prompt = persona.map(|prompt| prompt.as_str())
+        .or_else(|| args.auto_detect_language.then(|| language::system_prompt(input)).flatten())
+        .or(args.system_prompt.as_deref());
+    let system_prompt = match (datetime, prompt) {
+        (Some(datetime), Some(prompt)) => { Some(format!("{}\n{}", datetime, prompt)) }
+        (Some(datetime), None) => { Some(datetime) }
+        (None, prompt) => { prompt.map(|prompt| prompt.to_string()) }
+    };
+    if let Some(prompt) = system_prompt.filter(|_| !input.disable_system_prompt) {
+        request_messages.push(ChatCompletionRequestSystemMessageArgs::default()
+            .content(prompt)
+            .build()?.into());
+    }
+    if let Some(system) = &input.system {
+        if args.ignore_client_system {
+            warn!("dropped client system field");
+        } else {
+            if input.contents.iter().any(|content| get_role(content.role.clone()) == Role::System) {
+                warn!("both system and system messages in contents set, using system");
+            }
+            request_messages.push(ChatCompletionRequestSystemMessageArgs::default()
+                .content(system.as_str())
+                .build()?.into());
+        }
+    }
+    for example in examples {
+        request_messages.push(match example.role.as_str() {
+            "assistant" => {
+                ChatCompletionRequestAssistantMessageArgs::default()
+                    .content(example.content.as_str())
+                    .build()?.into()
+            }
+            _ => {
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(example.content.as_str())
+                    .build()?.into()
+            }
+        });
+    }
+    // 模板只套用在最后一条用户消息上
+    let template = input.template.as_ref().and_then(|name| CONFIG.get().unwrap().templates.get(name));
+    let template_index = input.contents.iter()
+        .rposition(|content| !matches!(get_role(content.role.clone()), Role::System | Role::Assistant));
+    for (index, content) in input.contents.iter().enumerate() {
+        let role = get_role(content.role.clone());
+        if role == Role::System && args.ignore_client_system {
+            warn!("dropped client system message");
+            continue;
+        }
+        if role == Role::System && input.system.is_some() {
+            continue;
+        }
+        let request_message: ChatCompletionRequestMessage = match role {
+            Role::System => {
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(content.content.clone())
+                    .build()?.into()
+            }
+            // 末尾的 assistant 消息原样转发,作为预填充让模型接着往下写
+            Role::Assistant => {
+                ChatCompletionRequestAssistantMessageArgs::default()
+                    .content(content.content.clone())
+                    .build()?.into()
+            }
+            _ => {
+                let text = match template {
+                    Some(template) if Some(index) == template_index => { template.replace("{input}", &content.content) }
+                    _ => { content.content.clone() }
+                };
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(text)
+                    .build()?.into()
+            }
+        };
+        request_messages.push(request_message);
+    }
+
+    let mut builder = CreateChatCompletionRequestArgs::default();
+    builder.model(input.model.as_str()).messages(request_messages);
+    // o1 系列模型不再支持 max_tokens,改用 max_completion_tokens
+    if is_reasoning_model(&input.model) {
+        if input.max_tokens.is_some() && input.max_completion_tokens.is_some() {
+            warn!("both max_tokens and max_completion_tokens set for {}, using max_completion_tokens", input.model);
+        }
+        if let Some(max_tokens) = input.max_completion_tokens.or(input.max_tokens) {
+            builder.max_completion_tokens(max_tokens);
+        }
+    } else if let Some(max_tokens) = input.max_tokens {
+        builder.max_tokens(max_tokens);
+    } else if let Some(max_tokens) = input.max_completion_tokens {
+        builder.max_completion_tokens(max_tokens);
+    }
+    if let Some(temperature) = input.temperature {
+        builder.temperature(temperature);
+    }
+    if let Some(mut stop) = stop_sequences(input) {
+        // --on-excess-stop clamp: 只保留前 4 个(合并时默认值在前)
+        if stop.len() > MAX_STOP_SEQUENCES {
+            warn!("{} stop sequences, keeping the first {}", stop.len(), MAX_STOP_SEQUENCES);
+            stop.truncate(MAX_STOP_SEQUENCES);
+        }
+        builder.stop(Stop::StringArray(stop));
+    }
+    if let Some(tools) = &input.tools {
+        builder.tools(tools.clone());
+    }
+    if let Some(parallel_tool_calls) = input.parallel_tool_calls {
+        builder.parallel_tool_calls(parallel_tool_calls);
+    }
+    if let Some(logprobs) = input.logprobs {
+        builder.logprobs(logprobs);
+    }
+    if let Some(top_logprobs) = input.top_logprobs {
+        builder.top_logprobs(top_logprobs);
+    }
+    if let Some(n) = input.variations.map(|variations| variations.saturating_add(1)).or(input.n) {
+        builder.n(n);
+    }
+    if let Some(json_schema) = &input.json_schema {
+        builder.response_format(schema::response_format(json_schema));
+    }
+    if let Some(service_tier) = input.service_tier.as_deref().and_then(parse_service_tier) {
+        builder.service_tier(service_tier);
+    }
+    Ok(builder.build()?)
+}
+
+// 合并 --default-stop 和请求中的 stop
+// OpenAI 最多接受 4 个停止序列
+const MAX_STOP_SEQUENCES: usize = 4;
+
+fn stop_sequences(input: &UserInput) -> Option<Vec<String>> {
+    let default_stop = &ARGS.get().unwrap().default_stop;
+    let stop = match (&input.stop, ARGS.get().unwrap().stop_merge) {
+        (None, _) => { default_stop.clone() }
+        (Some(stop), StopMerge::Override) => { stop.clone() }
+        (Some(stop), StopMerge::Append) => {
+            let mut merged = default_stop.clone();
+            merged.extend(stop.iter().filter(|s| !default_stop.contains(s)).cloned());
+            merged
+        }
+    };
+    (!stop.is_empty()).then_some(stop)
+}
+
+fn parse_service_tier(s: &str) -> Option<ServiceTier> {
+    match s {
+        "auto" => Some(ServiceTier::Auto),
+        "default" => Some(ServiceTier::Default),
+        _ => None,
+    }
+}
+
+fn is_reasoning_model(model: &str) -> bool {
+    model.starts_with("o1")
+}
+
+// 请求中的 timeout_secs 覆盖默认超时,但不能超过 --max-timeout
+fn request_timeout(input: &UserInput) -> Duration {
+    let args = ARGS.get().unwrap();
+    let secs = match input.timeout_secs {
+        None => { args.timeout }
+        Some(secs) if secs > args.max_timeout => {
+            warn!("timeout_secs {} exceeds max {}, clamped", secs, args.max_timeout);
+            args.max_timeout
+        }
+        Some(secs) => { secs }
+    };
+    Duration::from_secs(secs)
+}
+
+fn request_retries(input: &UserInput) -> u32 {
+    let args = ARGS.get().unwrap();
+    match input.max_retries.map(u32::from) {
+        None => { args.max_retries }
+        Some(retries) if retries > args.max_retries_limit => {
+            warn!("max_retries {} exceeds limit {}, clamped", retries, args.max_retries_limit);
+            args.max_retries_limit
+        }
+        Some(retries) => { retries }
+    }
+}
+
+fn model_allowed(model: &str) -> bool {
+    let args = ARGS.get().unwrap();
+    if args.deny_models.iter().any(|m| m == model) {
+        return false;
+    }
+    args.allow_models.is_empty() || args.allow_models.iter().any(|m| m == model)
+}
+
+// 去掉换行、制表符以外的控制字符
+fn sanitize(s: &str) -> String {
+    s.chars().filter(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t')).collect()
+}
+
+fn truncate_chars(s: &str, max_chars: usize) -> Option<String> {
+    s.char_indices().nth(max_chars).map(|(idx, _)| format!("{}...", &s[..idx]))
 }
 
 fn get_role(role: String) -> Role {
@@ -192,11 +1346,89 @@ fn get_role(role: String) -> Role {
     }
 }
 
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|err| format!("{}", err))?;
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(format!("{} is not in 0.0..=1.0", rate));
+    }
+    Ok(rate)
+}
+
+fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (key, value) = s.split_once('=').ok_or_else(|| format!("invalid KEY=VALUE: no `=` found in `{}`", s))?;
+    let name = HeaderName::from_bytes(key.trim().as_bytes()).map_err(|err| format!("{}", err))?;
+    let value = HeaderValue::from_str(value.trim()).map_err(|err| format!("{}", err))?;
+    Ok((name, value))
+}
+
+// 认证和传输相关的请求头由服务端和 HTTP 客户端设置,不能转发
+fn parse_forward_header(s: &str) -> Result<HeaderName, String> {
+    let name = HeaderName::from_bytes(s.trim().as_bytes()).map_err(|err| format!("{}", err))?;
+    if ["authorization", "api-key", "host", "content-length", "transfer-encoding", "connection"].contains(&name.as_str()) {
+        return Err(format!("{} cannot be forwarded", name));
+    }
+    Ok(name)
+}
+
+// /admin/config 中不输出密钥和请求头的值
+fn redact<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    value.as_ref().map(|_| "***").serialize(serializer)
+}
+
+fn header_name_list<S: Serializer>(names: &[HeaderName], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(names.iter().map(|name| name.as_str()))
+}
+
+fn header_names<S: Serializer>(headers: &[(HeaderName, HeaderValue)], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(headers.iter().map(|(name, _)| name.as_str()))
+}
+
+fn header_name<S: Serializer>(name: &axum::http::HeaderName, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(name.as_str())
+}
+
 fn time_diff(start_time: Instant, end_time: Instant) -> Duration {
     end_time.duration_since(start_time)
 }
 
-#[derive(Parser, Debug)]
+// 请求带 stop 时: override 只用请求的, append 追加在 --default-stop 之后
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum StopMerge {
+    Override,
+    Append,
+}
+
+// 请求的 max_tokens、n 或 stop 超过上限时: clamp 截到上限, reject 返回 400
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LimitPolicy {
+    Clamp,
+    Reject,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[command(about = "check a config file and exit without starting the server")]
+    ValidateConfig {
+        path: Option<String>,
+    },
+    #[command(about = "fire concurrent requests at the upstream and report latency percentiles")]
+    Benchmark {
+        #[arg(short = 'n', long, default_value_t = 100)]
+        requests: usize,
+        #[arg(short, long, default_value_t = 10)]
+        concurrency: usize,
+        #[arg(short, long, default_value_t = default_model())]
+        model: String,
+        #[arg(long, default_value = "hello")]
+        prompt: String,
+        #[arg(long, default_value_t = 16)]
+        max_tokens: u32,
+    },
+}
+
+#[derive(Parser, Serialize, Debug)]
 #[command(long_about = None)]
 #[command(name = "clap_demo")]
 #[command(author = "song")]
@@ -204,8 +1436,304 @@ fn time_diff(start_time: Instant, end_time: Instant) -> Duration {
 #[command(about = "openai_demo about")]
 struct Args {
     #[arg(short, long, default_value = None)]
+    #[serde(serialize_with = "redact")]
     api_key: Option<String>,
+    #[arg(long)]
+    api_key_file: Option<String>,
     #[arg(short, long, default_value_t = 10802)]
     port: u16,
+    #[arg(long, value_enum, default_value_t = Provider::Openai)]
+    provider: Provider,
+    #[arg(long)]
+    api_base: Option<String>,
+    #[arg(long)]
+    api_version: Option<String>,
+    #[arg(long)]
+    tls_cert: Option<String>,
+    #[arg(long)]
+    tls_key: Option<String>,
+    #[arg(long, value_enum, default_value_t = TlsVersion::Tls12)]
+    min_tls_version: TlsVersion,
+    #[arg(long, value_delimiter = ',')]
+    tls_ciphers: Vec<String>,
+    #[arg(long, default_value_t = 5)]
+    breaker_threshold: u32,
+    #[arg(long, default_value_t = 30)]
+    breaker_cooldown: u64,
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_header)]
+    #[serde(serialize_with = "header_names")]
+    upstream_header: Vec<(HeaderName, HeaderValue)>,
+    #[arg(long, value_delimiter = ',', value_parser = parse_forward_header)]
+    #[serde(serialize_with = "header_name_list")]
+    forward_headers: Vec<HeaderName>,
+    #[arg(long)]
+    max_response_chars: Option<usize>,
+    #[arg(long)]
+    max_response_tokens: Option<usize>,
+    #[arg(long, default_value_t = false)]
+    sanitize_output: bool,
+    #[arg(long, default_value_t = false)]
+    strip_markdown: bool,
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+    #[arg(long, value_delimiter = ',')]
+    allow_models: Vec<String>,
+    #[arg(long, value_delimiter = ',')]
+    deny_models: Vec<String>,
+    #[arg(long, value_delimiter = ',', value_parser = ip_filter::parse_cidr)]
+    allow_ip: Vec<IpNet>,
+    #[arg(long, value_delimiter = ',', value_parser = ip_filter::parse_cidr)]
+    deny_ip: Vec<IpNet>,
+    #[arg(long, default_value = "x-request-id", value_parser = axum::http::HeaderName::from_str)]
+    #[serde(serialize_with = "header_name")]
+    request_id_header: axum::http::HeaderName,
+    #[arg(short, long)]
+    config: Option<String>,
+    #[arg(long, default_value_t = false)]
+    warmup: bool,
+    #[arg(long, default_value_t = 4)]
+    compare_concurrency: usize,
+    #[arg(long, default_value_t = 4)]
+    batch_concurrency: usize,
+    #[arg(long, default_value_t = 3000, value_parser = clap::value_parser!(u64).range(1..))]
+    chunk_size: u64,
+    #[arg(long, default_value_t = 4)]
+    summarize_concurrency: usize,
+    #[arg(long, default_value_t = 15)]
+    sse_keepalive: u64,
+    #[arg(long, default_value_t = 10)]
+    shutdown_timeout: u64,
+    #[arg(long, default_value_t = 0)]
+    max_streams: usize,
+    #[arg(long, default_value_t = false)]
+    load_headers: bool,
+    #[arg(long, default_value_t = false)]
+    prompt_hash_analytics: bool,
+    #[arg(long)]
+    #[serde(serialize_with = "redact")]
+    prompt_hash_secret: Option<String>,
+    #[arg(long, default_value_t = 1000)]
+    sse_buffer: usize,
+    #[arg(long, default_value_t = 60)]
+    sse_resume_ttl: u64,
+    #[arg(long, default_value_t = false)]
+    stream_fanout: bool,
+    #[arg(long)]
+    audit_log: Option<String>,
+    #[arg(long, default_value_t = false)]
+    audit_log_content: bool,
+    #[arg(long)]
+    escalate_model: Option<String>,
+    #[arg(long, value_delimiter = ',')]
+    escalate_on: Vec<String>,
+    #[arg(long, default_value_t = false)]
+    legacy_response: bool,
+    #[arg(long, default_value_t = false)]
+    strict_body: bool,
+    #[arg(long, default_value_t = false)]
+    lenient_content_type: bool,
+    #[arg(long, default_value_t = false)]
+    require_content_length: bool,
+    #[arg(long, default_value_t = 2 * 1024 * 1024)]
+    max_body_size: u64,
+    #[arg(long, default_value_t = 30)]
+    body_read_timeout: u64,
+    #[arg(long)]
+    max_request_age: Option<u64>,
+    #[arg(long, default_value_t = 300)]
+    idempotency_ttl: u64,
+    #[arg(long, default_value_t = false)]
+    auto_truncate_context: bool,
+    #[arg(long, default_value_t = false)]
+    partial_on_timeout: bool,
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    log_sample_rate: f64,
+    #[arg(long, value_enum, default_value_t = BlockInjection::Off)]
+    block_injection: BlockInjection,
+    #[arg(long)]
+    max_tokens_limit: Option<u32>,
+    #[arg(long, value_enum, default_value_t = LimitPolicy::Clamp)]
+    max_tokens_policy: LimitPolicy,
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+    default_n: Option<u8>,
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+    max_n: Option<u8>,
+    #[arg(long, value_enum, default_value_t = LimitPolicy::Clamp)]
+    n_policy: LimitPolicy,
+    #[arg(long, default_value_t = 3)]
+    max_variations: u8,
+    #[arg(long, default_value_t = 2)]
+    schema_retries: u32,
+    #[arg(long, default_value_t = false)]
+    retry_empty_choices: bool,
+    #[arg(long)]
+    system_prompt: Option<String>,
+    #[arg(long)]
+    default_stop: Vec<String>,
+    #[arg(long, value_enum, default_value_t = StopMerge::Override)]
+    stop_merge: StopMerge,
+    #[arg(long, value_enum, default_value_t = LimitPolicy::Reject)]
+    on_excess_stop: LimitPolicy,
+    #[arg(long, default_value_t = false)]
+    inject_datetime: bool,
+    #[arg(long, default_value_t = false)]
+    auto_detect_language: bool,
+    #[arg(long, default_value = "UTC")]
+    timezone: chrono_tz::Tz,
+    #[arg(long, default_value_t = false)]
+    ignore_client_system: bool,
+    #[arg(long, default_value_t = 0)]
+    max_retries: u32,
+    #[arg(long, default_value_t = 5)]
+    max_retries_limit: u32,
+    #[arg(long, default_value_t = 10.0)]
+    retry_budget: f64,
+    #[arg(long, default_value_t = 0)]
+    throttle_threshold: u64,
+    #[arg(long, default_value_t = 10)]
+    throttle_max_wait: u64,
+    #[arg(long, visible_alias = "timeout-nonstreaming", default_value_t = 120)]
+    timeout: u64,
+    #[arg(long, default_value_t = 60)]
+    timeout_streaming: u64,
+    #[arg(long, default_value_t = 0)]
+    first_token_timeout: u64,
+    #[arg(long, default_value_t = 0)]
+    stream_autoresume: u32,
+    #[arg(long, default_value_t = 0)]
+    stream_flush_ms: u64,
+    #[arg(long, default_value_t = 600)]
+    max_timeout: u64,
+    #[arg(long, default_value_t = 10)]
+    connect_timeout: u64,
+    #[arg(long, default_value_t = 60)]
+    read_timeout: u64,
+    #[arg(long, default_value_t = 0)]
+    client_max_lifetime: u64,
+    #[arg(long, default_value = concat!("kyf_server/", env!("CARGO_PKG_VERSION")))]
+    user_agent: String,
+    #[arg(long)]
+    #[serde(serialize_with = "redact")]
+    admin_token: Option<String>,
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::OnceLock;
+
+    use futures::StreamExt;
+
+    use super::*;
+
+    // 假的 OpenAI 上游: 每个请求以 SSE 返回三个增量,每个之后停 300ms
+    fn fake_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue; };
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                            break;
+                        }
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                length = value.trim().parse().unwrap();
+                            }
+                        }
+                    }
+                    reader.read_exact(&mut vec![0; length]).unwrap();
+                    stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\n").unwrap();
+                    for word in ["one ", "two ", "three"] {
+                        let chunk = json!({
+                            "id":"chatcmpl-test",
+                            "object":"chat.completion.chunk",
+                            "created":0,
+                            "model":"gpt-3.5-turbo",
+                            "choices":[{"index":0,"delta":{"content":word},"finish_reason":null}],
+                        });
+                        stream.write_all(format!("data: {}\n\n", chunk).as_bytes()).unwrap();
+                        std::thread::sleep(Duration::from_millis(300));
+                    }
+                    let _ = stream.write_all(b"data: [DONE]\n\n");
+                });
+            }
+        });
+        addr
+    }
+
+    // 测试共用默认参数、空配置和指向假上游的全局状态,与 main 中的初始化相同
+    fn init() {
+        static INIT: OnceLock<()> = OnceLock::new();
+        INIT.get_or_init(|| {
+            let api_base = format!("http://{}", fake_upstream());
+            let args = Args::parse_from(["openai_api_server", "--api-base", api_base.as_str()]);
+            let _ = CONFIG.set(Config::default());
+            let _ = API_KEY.set("test".to_string());
+            let _ = CIRCUIT_BREAKER.set(CircuitBreaker::new(args.breaker_threshold, Duration::from_secs(args.breaker_cooldown), Duration::from_secs(args.timeout)));
+            let _ = RETRY_BUDGET.set(RetryBudget::new(args.retry_budget));
+            let _ = STREAM_PERMITS.set(Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)));
+            let _ = THROTTLE.set(Throttle::new(args.throttle_threshold, Duration::from_secs(args.throttle_max_wait)));
+            let _ = HTTP_CLIENT.set(RwLock::new(build_http_client(&args).unwrap()));
+            let _ = ARGS.set(args);
+        });
+    }
+
+    // h2c(prior knowledge)连接上的 SSE 事件要随上游增量陆续到达,而不是在流结束时一起到达
+    #[tokio::test]
+    async fn sse_over_http2_is_incremental() {
+        init();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(router().into_make_service_with_connect_info::<SocketAddr>()));
+
+        let client = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+        let response = client.post(format!("http://{}/chat/stream", addr))
+            .json(&json!({"contents":[{"role":"user","content":"hello"}]}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        let start = Instant::now();
+        let mut body = response.bytes_stream();
+        let mut text = String::new();
+        let mut first = None;
+        while let Some(chunk) = body.next().await {
+            text.push_str(&String::from_utf8_lossy(&chunk.unwrap()));
+            if first.is_none() && text.contains("one") {
+                first = Some(start.elapsed());
+            }
+        }
+        let first = first.expect("first delta not received");
+        assert!(text.contains("three"));
+        assert!(start.elapsed() - first >= Duration::from_millis(500), "events arrived together: {:?} of {:?}", first, start.elapsed());
+    }
+
+    #[test]
+    fn trailing_assistant_message_is_forwarded() {
+        init();
+        let input: UserInput = serde_json::from_value(json!({
+            "model":"gpt-3.5-turbo",
+            "contents":[
+                {"role":"user","content":"Write a story"},
+                {"role":"assistant","content":"Once upon a time"},
+            ],
+        })).unwrap();
+        let request = build_chat_request(&input, &[]).unwrap();
+        let messages = serde_json::to_value(&request.messages).unwrap();
+        assert_eq!(messages, json!([
+            {"role":"user","content":"Write a story"},
+            {"role":"assistant","content":"Once upon a time"},
+        ]));
+    }
+}
@@ -0,0 +1,114 @@
+// 去掉回复中的 markdown 格式,保留文字内容;代码块只去掉围栏
+pub fn strip(s: &str) -> String {
+    let mut lines = Vec::new();
+    let mut in_code = false;
+    for raw in s.lines() {
+        let trimmed = raw.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            lines.push(raw.to_string());
+            continue;
+        }
+        if is_rule(trimmed) {
+            continue;
+        }
+        let mut line = trimmed;
+        while let Some(rest) = line.strip_prefix('>') {
+            line = rest.trim_start();
+        }
+        let hashes = line.chars().take_while(|c| *c == '#').count();
+        if (1..=6).contains(&hashes) && line[hashes..].starts_with(' ') {
+            line = line[hashes..].trim().trim_end_matches('#').trim_end();
+        }
+        // 保留列表的缩进层级
+        let indent = &raw[..raw.len() - trimmed.len()];
+        let line = match line.strip_prefix("* ").or_else(|| line.strip_prefix("+ ")) {
+            Some(item) => { format!("- {}", strip_inline(item)) }
+            None => { strip_inline(line) }
+        };
+        lines.push(format!("{}{}", indent, line));
+    }
+    lines.join("\n")
+}
+
+// ---、*** 和 ___ 分隔线
+fn is_rule(line: &str) -> bool {
+    let chars: Vec<char> = line.chars().filter(|c| !c.is_whitespace()).collect();
+    chars.len() >= 3 && matches!(chars[0], '-' | '*' | '_') && chars.iter().all(|c| *c == chars[0])
+}
+
+fn strip_inline(s: &str) -> String {
+    let chars: Vec<char> = s.chars().collect();
+    let mut out = String::with_capacity(s.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            // 行内代码: 原样保留其中的内容
+            '`' => {
+                let run = chars[i..].iter().take_while(|c| **c == '`').count();
+                let fence = &chars[i..i + run];
+                match find(&chars, i + run, fence) {
+                    Some(end) => {
+                        out.extend(&chars[i + run..end]);
+                        i = end + run;
+                    }
+                    None => {
+                        out.extend(fence);
+                        i += run;
+                    }
+                }
+            }
+            // 链接和图片只保留文字
+            '!' if chars.get(i + 1) == Some(&'[') => { i += 1; }
+            '[' => {
+                match link_end(&chars, i) {
+                    Some((text_end, end)) => {
+                        let text: String = chars[i + 1..text_end].iter().collect();
+                        out.push_str(&strip_inline(&text));
+                        i = end + 1;
+                    }
+                    None => {
+                        out.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            '*' | '_' | '~' => {
+                let run = chars[i..].iter().take_while(|x| **x == c).count();
+                let before = if i == 0 { None } else { Some(chars[i - 1]) };
+                let after = chars.get(i + run).copied();
+                let word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+                let space = |c: Option<char>| c.is_none_or(char::is_whitespace);
+                // snake_case、2 * 3 这类不是强调标记
+                let marker = run <= 3 && !(word(before) && word(after)) && !(space(before) && space(after));
+                if !marker || (c == '~' && run != 2) {
+                    out.extend(&chars[i..i + run]);
+                }
+                i += run;
+            }
+            _ => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+fn find(chars: &[char], from: usize, pattern: &[char]) -> Option<usize> {
+    (from..chars.len()).find(|&i| chars[i..].starts_with(pattern))
+}
+
+// [text](url): 返回 ] 和 ) 的位置
+fn link_end(chars: &[char], start: usize) -> Option<(usize, usize)> {
+    let text_end = start + chars[start..].iter().position(|c| *c == ']')?;
+    if chars.get(text_end + 1) != Some(&'(') {
+        return None;
+    }
+    let end = text_end + 1 + chars[text_end + 1..].iter().position(|c| *c == ')')?;
+    Some((text_end, end))
+}
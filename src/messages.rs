@@ -0,0 +1,42 @@
+use crate::CONFIG;
+
+// 返回给客户端的固定提示,可在配置文件 [messages] 中按键覆盖,例如:
+// [messages]
+// no_content = "模型没有返回内容"
+pub const DEFAULTS: [(&str, &str); 25] = [
+    ("api_key_empty", "api_key is empty"),
+    ("no_choices", "no choices"),
+    ("no_content", "no content"),
+    ("invalid_request", "invalid request"),
+    ("upstream_unavailable", "upstream unavailable"),
+    ("request_cancelled", "request cancelled"),
+    ("cancelled", "cancelled"),
+    ("request_not_found", "request not found"),
+    ("unauthorized", "unauthorized"),
+    ("forbidden", "forbidden"),
+    ("admin_disabled", "admin api is disabled"),
+    ("stream_not_supported", "stream is not supported here, use /chat/stream for streaming responses"),
+    ("too_many_streams", "too many streams"),
+    ("server_shutting_down", "server shutting down"),
+    ("missing_content_type", "missing Content-Type header, expected application/json"),
+    ("requests_empty", "requests must not be empty"),
+    ("models_empty", "models must not be empty"),
+    ("compare_stream_not_supported", "stream is not supported by /chat/compare"),
+    ("context_length_exceeded", "context length exceeded"),
+    ("prompt_injection", "request blocked: possible prompt injection"),
+    ("schema_mismatch", "response does not match json_schema"),
+    ("length_required", "Content-Length header is required"),
+    ("body_too_large", "request body too large"),
+    ("body_timeout", "request body not received in time"),
+    ("idempotency_key_reused", "Idempotency-Key was already used with a different request body"),
+];
+
+pub fn get(key: &str) -> String {
+    if let Some(message) = CONFIG.get().and_then(|config| config.messages.get(key)) {
+        return message.clone();
+    }
+    DEFAULTS.iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, message)| message.to_string())
+        .unwrap_or_else(|| key.to_string())
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use async_openai::types::CompletionUsage;
+use axum::extract::Query;
+use axum::Json;
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tracing::{info, warn};
+
+use crate::{ARGS, envelope, tokenizer, UserInput};
+
+// 最多统计这么多个不同的哈希,之后出现的新哈希只计入 untracked
+const MAX_TRACKED: usize = 100_000;
+
+#[derive(Default)]
+struct Entry {
+    count: u64,
+    prompt_tokens: u64,
+    models: HashMap<String, u64>,
+}
+
+#[derive(Default)]
+struct Stats {
+    total: u64,
+    untracked: u64,
+    entries: HashMap<String, Entry>,
+}
+
+static STATS: LazyLock<Mutex<Stats>> = LazyLock::new(|| Mutex::new(Stats::default()));
+
+// 不带密钥的哈希可以用常见提示词逐个试出原文,所以用 --prompt-hash-secret 作为 HMAC 密钥;
+// 没有配置时每次启动随机生成,哈希在重启后会变
+static SECRET: LazyLock<String> = LazyLock::new(|| {
+    match &ARGS.get().unwrap().prompt_hash_secret {
+        Some(secret) => { secret.clone() }
+        None => {
+            warn!("--prompt-hash-secret not set, prompt hashes will change after restart");
+            format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+        }
+    }
+});
+
+// 规整后的消息(角色 + 去掉首尾空白、合并连续空白的内容)的 HMAC-SHA256,取前 16 字节
+pub fn hash(input: &UserInput) -> String {
+    let mut normalized = String::new();
+    let system = input.system.iter().map(|system| ("system", system.as_str()));
+    let contents = input.contents.iter().map(|content| (content.role.as_str(), content.content.as_str()));
+    for (role, content) in system.chain(contents) {
+        normalized.push_str(&role.trim().to_lowercase());
+        normalized.push('\u{1f}');
+        normalized.push_str(&content.split_whitespace().collect::<Vec<_>>().join(" "));
+        normalized.push('\u{1e}');
+    }
+    keyed_hash(SECRET.as_bytes(), &normalized)
+}
+
+fn keyed_hash(secret: &[u8], data: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes()[..16].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// --prompt-hash-analytics: 只记录哈希、模型和 token 数,不记录消息内容
+// 没有 usage 时(流式请求)按本地 tokenizer 估算
+pub fn record(input: &UserInput, usage: Option<&CompletionUsage>) {
+    if !ARGS.get().unwrap().prompt_hash_analytics {
+        return;
+    }
+    let hash = hash(input);
+    let prompt_tokens = match usage {
+        Some(usage) => { usage.prompt_tokens as u64 }
+        None => {
+            let system = input.system.as_deref().map(|system| tokenizer::count_tokens(&input.model, system)).unwrap_or(0);
+            (system + input.contents.iter().map(|content| tokenizer::count_tokens(&input.model, &content.content)).sum::<usize>()) as u64
+        }
+    };
+    info!(prompt_hash = %hash, model = %input.model, prompt_tokens, "prompt hash");
+    let mut stats = STATS.lock();
+    stats.total += 1;
+    if !stats.entries.contains_key(&hash) && stats.entries.len() >= MAX_TRACKED {
+        stats.untracked += 1;
+        return;
+    }
+    let entry = stats.entries.entry(hash).or_default();
+    entry.count += 1;
+    entry.prompt_tokens += prompt_tokens;
+    *entry.models.entry(input.model.clone()).or_default() += 1;
+}
+
+#[derive(Deserialize)]
+pub struct StatsParams {
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    20
+}
+
+// repeated 为重复出现的请求数(同一哈希第二次及以后),即完全缓存时可以省掉的请求数
+pub async fn stats_handler(Query(params): Query<StatsParams>) -> Json<Value> {
+    let stats = STATS.lock();
+    let unique = stats.entries.len() as u64;
+    let repeated = stats.total - stats.untracked - unique;
+    let mut top: Vec<(&String, &Entry)> = stats.entries.iter().filter(|(_, entry)| entry.count > 1).collect();
+    top.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(b.0)));
+    let top: Vec<Value> = top.into_iter().take(params.limit).map(|(hash, entry)| json!({
+        "hash":hash,
+        "count":entry.count,
+        "prompt_tokens":entry.prompt_tokens,
+        "models":entry.models,
+    })).collect();
+    Json(envelope(json!({
+        "code":200u16,
+        "enabled":ARGS.get().unwrap().prompt_hash_analytics,
+        "total":stats.total,
+        "unique":unique,
+        "repeated":repeated,
+        "untracked":stats.untracked,
+        "top":top,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyed_hash_is_truncated_hmac_sha256() {
+        // RFC 4231 test case 2
+        assert_eq!(keyed_hash(b"Jefe", "what do ya want for nothing?"), "5bdcc146bf60754e6a042426089575c7");
+        assert_ne!(keyed_hash(b"a", "user\u{1f}hello\u{1e}"), keyed_hash(b"b", "user\u{1f}hello\u{1e}"));
+    }
+}
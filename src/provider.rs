@@ -0,0 +1,126 @@
+use async_openai::config::{AzureConfig, Config, OpenAIConfig};
+use clap::ValueEnum;
+use reqwest::header::{HeaderMap, HeaderValue};
+use secrecy::SecretString;
+use serde::Serialize;
+
+use crate::ARGS;
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    Openai,
+    Azure,
+}
+
+// azure 的部署名在路径里: {api_base}/openai/deployments/{model}/chat/completions?api-version=...
+#[derive(Clone, Debug)]
+enum Upstream {
+    OpenAI(OpenAIConfig),
+    Azure { config: AzureConfig, deployment: bool },
+}
+
+// forwarded 为按 --forward-headers 从客户端请求复制过来的请求头
+#[derive(Clone, Debug)]
+pub struct UpstreamConfig {
+    upstream: Upstream,
+    forwarded: HeaderMap,
+}
+
+impl UpstreamConfig {
+    // model 为空时不带部署名,用于 models 等账号级接口
+    pub fn new(api_key: String, model: &str) -> Self {
+        let args = ARGS.get().unwrap();
+        let api_base = args.api_base.as_deref().map(|base| base.trim_end_matches('/'));
+        let upstream = match args.provider {
+            Provider::Openai => {
+                let mut config = OpenAIConfig::default().with_api_key(api_key);
+                if let Some(api_base) = api_base {
+                    config = config.with_api_base(api_base);
+                }
+                Upstream::OpenAI(config)
+            }
+            Provider::Azure => {
+                let config = AzureConfig::new()
+                    .with_api_base(api_base.unwrap_or_default())
+                    .with_api_version(args.api_version.clone().unwrap_or_default())
+                    .with_deployment_id(model)
+                    .with_api_key(api_key);
+                Upstream::Azure { config, deployment: !model.is_empty() }
+            }
+        };
+        UpstreamConfig { upstream, forwarded: HeaderMap::new() }
+    }
+
+    // 只复制 --forward-headers 中列出的请求头,避免把客户端的其他请求头(如 Authorization、Cookie)泄露给上游
+    pub fn forward(mut self, headers: &axum::http::HeaderMap) -> Self {
+        for name in &ARGS.get().unwrap().forward_headers {
+            for value in headers.get_all(name.as_str()) {
+                if let Ok(value) = HeaderValue::from_bytes(value.as_bytes()) {
+                    self.forwarded.append(name.clone(), value);
+                }
+            }
+        }
+        self
+    }
+}
+
+impl Config for UpstreamConfig {
+    fn headers(&self) -> HeaderMap {
+        let mut headers = match &self.upstream {
+            Upstream::OpenAI(config) => { config.headers() }
+            Upstream::Azure { config, .. } => { config.headers() }
+        };
+        // 客户端的值代替默认值(如 async-openai 默认的 OpenAI-Beta);认证相关的请求头不允许转发
+        for name in self.forwarded.keys() {
+            headers.remove(name);
+        }
+        for (name, value) in &self.forwarded {
+            headers.append(name.clone(), value.clone());
+        }
+        headers
+    }
+
+    fn url(&self, path: &str) -> String {
+        match &self.upstream {
+            Upstream::OpenAI(config) => { config.url(path) }
+            Upstream::Azure { config, deployment: true } => { config.url(path) }
+            Upstream::Azure { config, deployment: false } => { format!("{}/openai{}", config.api_base(), path) }
+        }
+    }
+
+    fn query(&self) -> Vec<(&str, &str)> {
+        match &self.upstream {
+            Upstream::OpenAI(config) => { config.query() }
+            Upstream::Azure { config, .. } => { config.query() }
+        }
+    }
+
+    fn api_base(&self) -> &str {
+        match &self.upstream {
+            Upstream::OpenAI(config) => { config.api_base() }
+            Upstream::Azure { config, .. } => { config.api_base() }
+        }
+    }
+
+    fn api_key(&self) -> &SecretString {
+        match &self.upstream {
+            Upstream::OpenAI(config) => { config.api_key() }
+            Upstream::Azure { config, .. } => { config.api_key() }
+        }
+    }
+}
+
+// 启动时检查: azure 必须指定资源地址和 api-version
+pub fn check(provider: Provider, api_base: Option<&str>, api_version: Option<&str>) -> Result<(), String> {
+    if provider != Provider::Azure {
+        return Ok(());
+    }
+    if api_base.is_none_or(str::is_empty) {
+        return Err("--api-base is required when --provider azure".to_string());
+    }
+    if api_version.is_none_or(str::is_empty) {
+        return Err("--api-version is required when --provider azure".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::extract::ConnectInfo;
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use sha2::{Digest, Sha256};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::ARGS;
+
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    // 按请求ID的哈希(FNV-1a)决定是否抽中,同一个请求ID每次结果相同
+    pub fn sampled(&self, rate: f64) -> bool {
+        let hash = self.0.bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+        (hash as f64 / u64::MAX as f64) < rate
+    }
+}
+
+// 发起请求的客户端地址
+#[derive(Clone, Copy, Debug)]
+pub struct Peer(pub Option<IpAddr>);
+
+// 请求ID由客户端决定,取消和断线重连只允许原来的调用方操作:
+// 请求带了 api_key 时按 api_key 的哈希区分,否则按客户端地址区分
+pub fn owner(api_key: &str, peer: Peer) -> String {
+    if !api_key.is_empty() {
+        let digest = Sha256::digest(api_key.as_bytes());
+        return format!("key:{}", digest[..16].iter().map(|byte| format!("{:02x}", byte)).collect::<String>());
+    }
+    match peer.0 {
+        None => { "unknown".to_string() }
+        Some(ip) => { format!("ip:{}", ip) }
+    }
+}
+
+// 优先沿用客户端传入的请求ID,没有则生成一个,并在响应头中原样返回
+pub async fn request_id<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let header = ARGS.get().unwrap().request_id_header.clone();
+    let id = match req.headers().get(&header).and_then(|value| value.to_str().ok()) {
+        Some(s) if !s.is_empty() => { s.to_string() }
+        _ => { Uuid::new_v4().to_string() }
+    };
+    req.extensions_mut().insert(RequestId(id.clone()));
+    let peer = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip().to_canonical());
+    req.extensions_mut().insert(Peer(peer));
+
+    let span = tracing::info_span!("request", request_id = %id);
+    let mut response = next.run(req).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(header, value);
+    }
+    response
+}
@@ -0,0 +1,176 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use axum::response::sse::Event;
+use parking_lot::Mutex;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::ARGS;
+
+// 流式请求的事件缓冲: request_id -> 已发送的事件。客户端断线后带 Last-Event-ID 重连时从这里补发,
+// 每个请求最多保留 --sse-buffer 个事件,生成结束后再保留 --sse-resume-ttl 秒
+static STREAMS: LazyLock<Mutex<HashMap<String, Arc<Buffer>>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+// --stream-fanout: 进行中的生成,请求内容的哈希 -> 事件缓冲;生成结束时移除
+static LIVE: LazyLock<Mutex<HashMap<String, Arc<Buffer>>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, Debug)]
+struct Stored {
+    id: u64,
+    event: Option<&'static str>,
+    data: String,
+}
+
+impl Stored {
+    fn to_event(&self) -> Event {
+        let event = Event::default().id(self.id.to_string()).data(&self.data);
+        match self.event {
+            None => { event }
+            Some(name) => { event.event(name) }
+        }
+    }
+}
+
+pub struct Buffer {
+    // 发起方(见 request_id::owner),断线重连时须相同
+    owner: String,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    events: VecDeque<Stored>,
+    next_id: u64,
+    // 生成结束后为 None,订阅者随之结束
+    sender: Option<broadcast::Sender<Stored>>,
+}
+
+// 生成事件的一端,drop 时表示生成结束
+pub struct Publisher {
+    request_id: String,
+    fanout_key: Option<String>,
+    buffer: Arc<Buffer>,
+}
+
+pub fn publish(request_id: &str, fanout_key: Option<String>, owner: String) -> Publisher {
+    let capacity = ARGS.get().unwrap().sse_buffer.max(1);
+    let buffer = Arc::new(Buffer {
+        owner,
+        inner: Mutex::new(Inner {
+            events: VecDeque::new(),
+            next_id: 1,
+            sender: Some(broadcast::channel(capacity).0),
+        }),
+    });
+    STREAMS.lock().insert(request_id.to_string(), buffer.clone());
+    if let Some(key) = &fanout_key {
+        LIVE.lock().insert(key.clone(), buffer.clone());
+    }
+    Publisher { request_id: request_id.to_string(), fanout_key, buffer }
+}
+
+// 不是同一个发起方时与请求不存在一样处理
+pub fn find(request_id: &str, owner: &str) -> Option<Arc<Buffer>> {
+    STREAMS.lock().get(request_id).filter(|buffer| buffer.owner == owner).cloned()
+}
+
+pub fn find_live(fanout_key: &str) -> Option<Arc<Buffer>> {
+    LIVE.lock().get(fanout_key).cloned()
+}
+
+impl Publisher {
+    pub fn buffer(&self) -> &Arc<Buffer> {
+        &self.buffer
+    }
+
+    pub fn send(&self, event: Option<&'static str>, data: String) {
+        let mut inner = self.buffer.inner.lock();
+        let stored = Stored { id: inner.next_id, event, data };
+        inner.next_id += 1;
+        inner.events.push_back(stored.clone());
+        if inner.events.len() > ARGS.get().unwrap().sse_buffer {
+            inner.events.pop_front();
+        }
+        if let Some(sender) = &inner.sender {
+            let _ = sender.send(stored);
+        }
+    }
+
+    // 没有任何连接(客户端断开且没有重连或订阅)超过 --sse-resume-ttl 秒时返回,
+    // 调用方应停止生成,释放上游请求和 --max-streams 许可
+    pub async fn abandoned(&self) {
+        let ttl = Duration::from_secs(ARGS.get().unwrap().sse_resume_ttl);
+        let mut since: Option<Instant> = None;
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let subscribed = self.buffer.inner.lock().sender.as_ref().is_some_and(|sender| sender.receiver_count() > 0);
+            if subscribed {
+                since = None;
+            } else if since.get_or_insert_with(Instant::now).elapsed() >= ttl {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        self.buffer.inner.lock().sender = None;
+        if let Some(key) = &self.fanout_key {
+            let mut live = LIVE.lock();
+            if live.get(key).map(|cached| Arc::ptr_eq(cached, &self.buffer)).unwrap_or(false) {
+                live.remove(key);
+            }
+        }
+        let request_id = std::mem::take(&mut self.request_id);
+        let buffer = self.buffer.clone();
+        let ttl = Duration::from_secs(ARGS.get().unwrap().sse_resume_ttl);
+        tokio::spawn(async move {
+            tokio::time::sleep(ttl).await;
+            let mut streams = STREAMS.lock();
+            // 同一 request_id 可能已被新的请求覆盖,只移除自己的
+            if streams.get(&request_id).map(|cached| Arc::ptr_eq(cached, &buffer)).unwrap_or(false) {
+                streams.remove(&request_id);
+            }
+        });
+    }
+}
+
+// 先补发 id 大于 last_id 的缓冲事件,再转发新的事件;
+// 客户端读得太慢、落后超过缓冲大小时断开连接,客户端可带 Last-Event-ID 重连
+pub fn subscribe(buffer: &Buffer, last_id: Option<u64>) -> ReceiverStream<Result<Event, Infallible>> {
+    let (backlog, receiver) = {
+        let inner = buffer.inner.lock();
+        let backlog: Vec<Stored> = inner.events.iter()
+            .filter(|stored| last_id.map(|last_id| stored.id > last_id).unwrap_or(true))
+            .cloned()
+            .collect();
+        (backlog, inner.sender.as_ref().map(|sender| sender.subscribe()))
+    };
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut last_id = last_id;
+        for stored in backlog {
+            if tx.send(Ok(stored.to_event())).await.is_err() {
+                return;
+            }
+            last_id = Some(stored.id);
+        }
+        let Some(mut receiver) = receiver else { return; };
+        // 客户端断开时立即退出,不再占用订阅
+        while let Some(Ok(stored)) = tokio::select! {
+            stored = receiver.recv() => { Some(stored) }
+            () = tx.closed() => { None }
+        } {
+            if last_id.map(|last_id| stored.id <= last_id).unwrap_or(false) {
+                continue;
+            }
+            last_id = Some(stored.id);
+            if tx.send(Ok(stored.to_event())).await.is_err() {
+                return;
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
@@ -0,0 +1,59 @@
+use std::time::Instant;
+
+use parking_lot::Mutex;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+// 全局重试预算(令牌桶),每秒补充 rate 个,最多积累 rate 个
+#[derive(Debug)]
+pub struct RetryBudget {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RetryBudget {
+    pub fn new(rate: f64) -> Self {
+        RetryBudget {
+            rate,
+            bucket: Mutex::new(Bucket { tokens: rate, last: Instant::now() }),
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut bucket = self.bucket.lock();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn panic_in_spawned_thread_does_not_poison() {
+        let budget = Arc::new(RetryBudget::new(1.0));
+        let shared = budget.clone();
+        let result = std::thread::spawn(move || {
+            let _bucket = shared.bucket.lock();
+            panic!("request panicked while holding the retry budget lock");
+        }).join();
+        assert!(result.is_err());
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+    }
+}
@@ -0,0 +1,31 @@
+use async_openai::types::{ResponseFormat, ResponseFormatJsonSchema};
+use serde_json::Value;
+
+// 请求中的 json_schema 本身必须是合法的 JSON Schema
+pub fn check(schema: &Value) -> Result<(), String> {
+    jsonschema::validator_for(schema).map(|_| ()).map_err(|err| format!("json_schema is invalid: {}", err))
+}
+
+pub fn response_format(schema: &Value) -> ResponseFormat {
+    ResponseFormat::JsonSchema {
+        json_schema: ResponseFormatJsonSchema {
+            description: None,
+            name: "response".to_string(),
+            schema: Some(schema.clone()),
+            strict: None,
+        },
+    }
+}
+
+// 模型输出须为符合 schema 的 JSON,不符合时返回全部校验错误
+pub fn validate(schema: &Value, output: &str) -> Result<Value, Vec<String>> {
+    let validator = jsonschema::validator_for(schema).map_err(|err| vec![err.to_string()])?;
+    let value: Value = serde_json::from_str(output).map_err(|err| vec![format!("output is not valid JSON: {}", err)])?;
+    let errors: Vec<String> = validator.iter_errors(&value)
+        .map(|err| format!("{}: {}", err.instance_path(), err))
+        .collect();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(value)
+}
@@ -0,0 +1,24 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::ARGS;
+
+static SHUTDOWN: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+pub fn trigger() {
+    SHUTDOWN.send_replace(true);
+}
+
+// 收到退出信号后返回,用于 axum 的 graceful shutdown
+pub async fn signal() {
+    let mut receiver = SHUTDOWN.subscribe();
+    let _ = receiver.wait_for(|shutdown| *shutdown).await;
+}
+
+// 退出信号之后再等 --shutdown-timeout 秒,给流式请求留出结束的时间
+pub async fn grace_expired() {
+    signal().await;
+    tokio::time::sleep(Duration::from_secs(ARGS.get().unwrap().shutdown_timeout)).await;
+}
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use axum::http::{HeaderValue, Request};
+use axum::Json;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde_json::{json, Value};
+
+use crate::{ARGS, CIRCUIT_BREAKER, envelope, STREAM_PERMITS, THROTTLE};
+
+static ACTIVE_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+// 正在处理的请求计数,drop 时减一;流式请求持有到流结束
+pub struct ActiveGuard;
+
+pub fn track() -> ActiveGuard {
+    ACTIVE_REQUESTS.fetch_add(1, Ordering::Relaxed);
+    ActiveGuard
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        ACTIVE_REQUESTS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// queue_depth 为因接近限流而在等待的请求数
+pub async fn status_handler() -> Json<Value> {
+    Json(envelope(json!({
+        "code":200u16,
+        "active_requests":ACTIVE_REQUESTS.load(Ordering::Relaxed),
+        "queue_depth":THROTTLE.get().unwrap().waiting(),
+        "circuit_breaker":CIRCUIT_BREAKER.get().unwrap().state(),
+    })))
+}
+
+// --load-headers: 在每个响应上附带负载信息,供客户端在多个实例间分流
+// X-Server-Load 设置了 --max-streams 时为 "打开的流式连接数/上限",客户端可按比例避开快满的实例;
+// 没有上限时为正在处理的请求数
+pub async fn load_headers<B>(req: Request<B>, next: Next<B>) -> Response {
+    let mut response = next.run(req).await;
+    if !ARGS.get().unwrap().load_headers {
+        return response;
+    }
+    let load = match ARGS.get().unwrap().max_streams {
+        0 => { ACTIVE_REQUESTS.load(Ordering::Relaxed).to_string() }
+        max => { format!("{}/{}", max.saturating_sub(STREAM_PERMITS.get().unwrap().available_permits()), max) }
+    };
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&load) {
+        headers.insert("x-server-load", value);
+    }
+    if let Some(remaining) = THROTTLE.get().unwrap().remaining_requests() {
+        headers.insert("x-ratelimit-remaining", HeaderValue::from(remaining));
+    }
+    response
+}
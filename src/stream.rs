@@ -0,0 +1,627 @@
+use std::collections::BTreeMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::convert::Infallible;
+use std::time::Duration;
+
+use async_openai::Client;
+use async_openai::error::OpenAIError;
+use async_openai::types::{ChatCompletionMessageToolCallChunk, CompletionUsage, ChatCompletionRequestAssistantMessageArgs, ChatCompletionResponseStream, ChatCompletionStreamOptions, CreateChatCompletionRequest};
+use axum::body::StreamBody;
+use axum::extract::Query;
+use axum::http::{header, HeaderMap};
+use axum::{Extension, Json};
+use axum::response::{IntoResponse, Response};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, Instrument, Span, warn};
+
+use crate::{audit, cancel, injection, messages, prompt_hash, request_id, resume, shutdown, status, tokenizer, upstream};
+use crate::coalesce::Coalescer;
+use crate::extract::ChatInput;
+use crate::provider::UpstreamConfig;
+use crate::request_id::{Peer, RequestId};
+use crate::status::ActiveGuard;
+use crate::{apply_defaults, ARGS, build_chat_request, build_config, ChatRequestError, CIRCUIT_BREAKER, cost_usd, envelope, examples, http_client, limit_max_tokens, limit_n, model_allowed, resolve_api_key, RETRY_BUDGET, sanitize, STREAM_PERMITS, THROTTLE, UserInput};
+
+#[derive(Deserialize, Debug)]
+pub struct StreamParams {
+    #[serde(default, rename = "final")]
+    pub final_event: bool,
+    #[serde(default)]
+    pub progress: bool,
+    // 覆盖 --stream-flush-ms,0 表示立即发送
+    pub flush_ms: Option<u64>,
+}
+
+// ?progress=true 时每新增这么多 token 发送一次 progress 事件
+const PROGRESS_STEP: usize = 16;
+
+// 已建立的上游流,连同并发计数和 --max-streams 的许可一起持有到流结束
+struct Upstream {
+    input: UserInput,
+    stream: ChatCompletionResponseStream,
+    resume: Resume,
+    active: ActiveGuard,
+    permit: OwnedSemaphorePermit,
+    // 整个流(包括续写)的 chat_completion span
+    span: Span,
+}
+
+// --stream-autoresume: 上游流中途断开时,把已收到的内容作为 assistant 消息(预填充)重新请求,
+// 让模型接着往下写,客户端看到的仍是一个连续的流
+struct Resume {
+    client: Client<UpstreamConfig>,
+    request: CreateChatCompletionRequest,
+    attempts: u32,
+    // 是否使用服务端密钥,见 upstream::upstream_fault
+    server_key: bool,
+}
+
+impl Resume {
+    // 超过次数、熔断打开或重试预算用完时返回 None,由调用方按原来的方式报错
+    // 同一次中断只向熔断报告一次: 没有重连时报告原来的错误,否则报告重连的结果
+    async fn reconnect(&mut self, partial: &str, err: &OpenAIError) -> Option<ChatCompletionResponseStream> {
+        let max_attempts = ARGS.get().unwrap().stream_autoresume;
+        if self.attempts >= max_attempts || !CIRCUIT_BREAKER.get().unwrap().allow() || !RETRY_BUDGET.get().unwrap().try_acquire() {
+            upstream::report(err, self.server_key);
+            return None;
+        }
+        self.attempts += 1;
+        warn!("stream interrupted after {} chars, resuming {}/{}: {}", partial.len(), self.attempts, max_attempts, err);
+        let mut request = self.request.clone();
+        if !partial.is_empty() {
+            request.messages.push(ChatCompletionRequestAssistantMessageArgs::default().content(partial).build().ok()?.into());
+        }
+        // allow() 可能把熔断从打开切到半开,这次重连就是探测请求,结果需要报告
+        let breaker = CIRCUIT_BREAKER.get().unwrap();
+        match self.client.chat().create_stream(request).await {
+            Ok(stream) => {
+                breaker.on_success();
+                Some(stream)
+            }
+            Err(err) => {
+                upstream::report(&err, self.server_key);
+                debug!("stream resume failed: {}", err);
+                None
+            }
+        }
+    }
+}
+
+// 按 index 累积流式增量中的工具调用,id、name 和 arguments 都可能分成多个增量到达
+#[derive(Default)]
+struct ToolCalls(BTreeMap<u32, (String, String, String)>);
+
+impl ToolCalls {
+    fn push(&mut self, chunks: &[ChatCompletionMessageToolCallChunk]) {
+        for call in chunks {
+            let (id, name, arguments) = self.0.entry(call.index).or_default();
+            if let Some(s) = &call.id {
+                id.push_str(s);
+            }
+            if let Some(function) = &call.function {
+                if let Some(s) = &function.name {
+                    name.push_str(s);
+                }
+                if let Some(s) = &function.arguments {
+                    arguments.push_str(s);
+                }
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn into_json(self) -> Vec<Value> {
+        self.0.into_values().map(|(id, name, arguments)| json!({
+            "id":id,
+            "type":"function",
+            "function":{"name":name,"arguments":arguments},
+        })).collect()
+    }
+}
+
+// 流式连接占用时间长,每个连接(包括断线重连和 --stream-fanout 的订阅者)占用一个 --max-streams 许可,
+// 连同并发计数一起持有到流结束;超过时直接拒绝
+fn acquire() -> Option<(ActiveGuard, OwnedSemaphorePermit)> {
+    let active = status::track();
+    let permit = STREAM_PERMITS.get().unwrap().clone().try_acquire_owned().ok()?;
+    Some((active, permit))
+}
+
+fn too_many_streams() -> Value {
+    json!({
+        "message":messages::get("too_many_streams"),
+        "code":503u16,
+    })
+}
+
+// 流式请求的前置检查和上游请求,失败时直接返回 JSON 响应
+async fn open(mut input: UserInput, include_usage: bool, headers: &HeaderMap, request_id: &str) -> Result<Upstream, Response> {
+    let Some((active, permit)) = acquire() else {
+        return Err(Json(envelope(audit::log(request_id, &input, too_many_streams()))).into_response());
+    };
+    if let Some(phrase) = injection::scan(&input) {
+        return Err(Json(envelope(audit::log(request_id, &input, json!({
+            "message":messages::get("prompt_injection"),
+            "code":403u16,
+            "phrase":phrase,
+        })))).into_response());
+    }
+    apply_defaults(&mut input, None);
+    if let Err(err) = limit_max_tokens(&mut input, None) {
+        return Err(Json(envelope(audit::log(request_id, &input, json!({
+            "message":messages::get("invalid_request"),
+            "code":400u16,
+            "errors":[err],
+        })))).into_response());
+    }
+    if let Err(err) = limit_n(&mut input) {
+        return Err(Json(envelope(audit::log(request_id, &input, json!({
+            "message":messages::get("invalid_request"),
+            "code":400u16,
+            "errors":[err],
+        })))).into_response());
+    }
+    if !model_allowed(&input.model) {
+        return Err(Json(envelope(audit::log(request_id, &input, json!({
+            "message":format!("model {} is not allowed",input.model),
+            "code":403u16,
+        })))).into_response());
+    }
+    let api_key = match resolve_api_key(&input.api_key) {
+        None => {
+            return Err(Json(envelope(audit::log(request_id, &input, json!({
+                "message":messages::get("api_key_empty"),
+                "code":500u16,
+            })))).into_response());
+        }
+        Some(key) => { key }
+    };
+    let client = Client::with_config(build_config(api_key, &input.model).forward(headers)).with_http_client(http_client());
+
+    let mut request = match build_chat_request(&input, examples(None)) {
+        Ok(s) => { s }
+        Err(ChatRequestError::Invalid(errors)) => {
+            return Err(Json(envelope(audit::log(request_id, &input, json!({
+                "message":messages::get("invalid_request"),
+                "code":400u16,
+                "errors":errors,
+            })))).into_response());
+        }
+        Err(err) => {
+            return Err(Json(envelope(audit::log(request_id, &input, json!({
+                "message":format!("{}",err),
+                "code":500u16,
+            })))).into_response());
+        }
+    };
+    if include_usage {
+        request.stream_options = Some(ChatCompletionStreamOptions { include_usage: true });
+    }
+    let breaker = CIRCUIT_BREAKER.get().unwrap();
+    if !breaker.allow() {
+        return Err(Json(envelope(audit::log(request_id, &input, json!({
+            "message":messages::get("upstream_unavailable"),
+            "code":503u16,
+        })))).into_response());
+    }
+    THROTTLE.get().unwrap().wait().await;
+    let span = upstream::span(&request.model, &input);
+    let stream = match client.chat().create_stream(request.clone()).instrument(span.clone()).await {
+        Ok(s) => {
+            breaker.on_success();
+            prompt_hash::record(&input, None);
+            s
+        }
+        Err(err) => {
+            upstream::report(&err, input.api_key.is_empty());
+            return Err(Json(envelope(audit::log(request_id, &input, json!({
+                "message":format!("{}",err),
+                "code":500u16,
+            })))).into_response());
+        }
+    };
+    let resume = Resume { client, request, attempts: 0, server_key: input.api_key.is_empty() };
+    Ok(Upstream { input, stream, resume, active, permit, span })
+}
+
+// 流式返回,每个增量一个事件: {"message":"<delta>","code":200}
+// 上游中途出错时,发送 error 事件并带上已收到的内容: {"message":"<err>","code":500,"partial":"<content>"}
+// 有工具调用时,结束时发送 tool_calls 事件: {"code":200,"tool_calls":[{"id":..,"type":"function","function":{..}}]}
+// ?final=true 时结束前额外发送 final 事件: {"message":"<content>","code":200,"usage":{..},"finish_reason":".."}
+// ?progress=true 时穿插 progress 事件,带已生成的 token 数: {"code":200,"type":"progress","tokens":N}
+// 每个事件带递增的 id;客户端断开后继续生成,重连时带 Last-Event-ID 可补发之后的事件
+// 超过 --timeout-streaming 秒没有收到上游的任何数据时结束流;每收到一个增量重新计时,为 0 时不限制
+async fn idle_timeout() {
+    match ARGS.get().unwrap().timeout_streaming {
+        0 => { std::future::pending().await }
+        secs => { tokio::time::sleep(Duration::from_secs(secs)).await }
+    }
+}
+
+// --first-token-timeout: 流开始后这么多秒内没有收到第一个 token 时结束流,收到后返回 None 不再限制
+fn first_token_deadline() -> Option<Instant> {
+    match ARGS.get().unwrap().first_token_timeout {
+        0 => { None }
+        secs => { Some(Instant::now() + Duration::from_secs(secs)) }
+    }
+}
+
+async fn first_token_timeout(deadline: Option<Instant>) {
+    match deadline {
+        None => { std::future::pending().await }
+        Some(deadline) => { tokio::time::sleep_until(deadline).await }
+    }
+}
+
+// 请求内容(含 api_key)和事件参数都相同才共享同一个生成
+fn fanout_key(input: &UserInput, params: &StreamParams) -> String {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(&(input, params.final_event, params.progress, params.flush_ms)).unwrap_or_default().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+pub async fn stream_handler(Extension(request_id): Extension<RequestId>, Extension(peer): Extension<Peer>, Query(params): Query<StreamParams>, headers: HeaderMap, ChatInput(input): ChatInput) -> Response {
+    let owner = request_id::owner(&input.api_key, peer);
+    // 断线重连: 带上原来的请求 id 和 Last-Event-ID,补发之后的事件
+    if let Some(last_id) = headers.get("last-event-id").and_then(|value| value.to_str().ok()) {
+        return match resume::find(&request_id.0, &owner) {
+            None => {
+                Json(envelope(json!({
+                    "message":messages::get("request_not_found"),
+                    "code":404u16,
+                }))).into_response()
+            }
+            Some(buffer) => {
+                let Some(guards) = acquire() else {
+                    return Json(envelope(too_many_streams())).into_response();
+                };
+                sse(resume::subscribe(&buffer, last_id.parse().ok()).map(move |event| {
+                    let _guards = &guards;
+                    event
+                }))
+            }
+        };
+    }
+    // 相同的请求正在生成时直接订阅同一个生成,从头补发缓冲中的事件
+    let fanout_key = ARGS.get().unwrap().stream_fanout.then(|| fanout_key(&input, &params));
+    if let Some(buffer) = fanout_key.as_deref().and_then(resume::find_live) {
+        let Some(guards) = acquire() else {
+            return Json(envelope(too_many_streams())).into_response();
+        };
+        debug!("joining in-flight stream");
+        return sse(resume::subscribe(&buffer, None).map(move |event| {
+            let _guards = &guards;
+            event
+        }));
+    }
+    let Upstream { input, stream: mut upstream, mut resume, active, permit, span } = match open(input, params.final_event, &headers, &request_id.0).await {
+        Ok(s) => { s }
+        Err(response) => { return response; }
+    };
+    let breaker = CIRCUIT_BREAKER.get().unwrap();
+
+    let publisher = resume::publish(&request_id.0, fanout_key, owner.clone());
+    let events = resume::subscribe(publisher.buffer(), None);
+    let mut cancellation = cancel::register(&request_id.0, owner);
+    tokio::spawn(async move {
+        let _active = active;
+        let _permit = permit;
+        // 流结束(包括中途出错、取消)时写一行审计日志,并在 span 上记录 token 用量
+        let audit = |code: u16, usage: Option<&CompletionUsage>, partial: &str| {
+            upstream::record_usage(&Span::current(), usage);
+            audit::record(&request_id.0, &input, code, usage, Some(partial).filter(|partial| !partial.is_empty()));
+        };
+        let model = &input.model;
+        let mut partial = String::new();
+        let mut usage = None;
+        let mut cost = None;
+        let mut finish_reason = Value::Null;
+        let mut tokens = 0;
+        let mut reported_tokens = 0;
+        // 工具调用的参数分散在多个增量中,按 index 拼接完整后再一次性发送
+        let mut tool_calls = ToolCalls::default();
+        let mut coalescer = Coalescer::new(params.flush_ms.unwrap_or(ARGS.get().unwrap().stream_flush_ms));
+        let mut first_token = first_token_deadline();
+        let shutdown = shutdown::grace_expired();
+        tokio::pin!(shutdown);
+        let abandoned = publisher.abandoned();
+        tokio::pin!(abandoned);
+        loop {
+            let chunk = tokio::select! {
+                chunk = upstream.next() => {
+                    match chunk {
+                        None => { break; }
+                        Some(chunk) => { chunk }
+                    }
+                }
+                Ok(()) = &mut cancellation.receiver => {
+                    debug!("stream cancelled");
+                    publisher.send(Some("error"), envelope(json!({
+                        "message":messages::get("request_cancelled"),
+                        "code":499u16,
+                        "partial":partial,
+                    })).to_string());
+                    audit(499, usage.as_ref(), &partial);
+                    return;
+                }
+                () = &mut shutdown => {
+                    debug!("stream terminated by shutdown");
+                    publisher.send(Some("error"), envelope(json!({
+                        "message":messages::get("server_shutting_down"),
+                        "code":503u16,
+                        "partial":partial,
+                    })).to_string());
+                    audit(503, usage.as_ref(), &partial);
+                    return;
+                }
+                () = &mut abandoned => {
+                    debug!("stream abandoned by client after {} chars", partial.len());
+                    audit(499, usage.as_ref(), &partial);
+                    return;
+                }
+                () = idle_timeout() => {
+                    breaker.on_failure();
+                    debug!("stream idle timeout after {} chars", partial.len());
+                    publisher.send(Some("error"), envelope(json!({
+                        "message":format!("upstream idle for {}s",ARGS.get().unwrap().timeout_streaming),
+                        "code":504u16,
+                        "partial":partial,
+                    })).to_string());
+                    audit(504, usage.as_ref(), &partial);
+                    return;
+                }
+                () = first_token_timeout(first_token) => {
+                    breaker.on_failure();
+                    debug!("no first token within {}s", ARGS.get().unwrap().first_token_timeout);
+                    publisher.send(Some("error"), envelope(json!({
+                        "message":format!("no token from upstream within {}s",ARGS.get().unwrap().first_token_timeout),
+                        "code":504u16,
+                        "partial":partial,
+                    })).to_string());
+                    audit(504, usage.as_ref(), &partial);
+                    return;
+                }
+                () = coalescer.expired() => {
+                    if let Some(text) = coalescer.flush_words() {
+                        publisher.send(None, envelope(json!({
+                            "message":text,
+                            "code":200u16,
+                        })).to_string());
+                    }
+                    continue;
+                }
+            };
+            let delta = match chunk {
+                Ok(chunk) => {
+                    // 第一个增量通常只有 role 和空内容,不算 token
+                    if chunk.choices.first().is_some_and(|choice| choice.delta.content.as_deref().is_some_and(|s| !s.is_empty()) || choice.delta.tool_calls.is_some()) {
+                        first_token = None;
+                    }
+                    if let Some(s) = &chunk.usage {
+                        usage = Some(s.clone());
+                        cost = cost_usd(model, Some(s));
+                    }
+                    if let Some(s) = chunk.choices.first().and_then(|choice| choice.finish_reason) {
+                        finish_reason = json!(s);
+                    }
+                    if let Some(chunks) = chunk.choices.first().and_then(|choice| choice.delta.tool_calls.as_ref()) {
+                        tool_calls.push(chunks);
+                    }
+                    let delta = match chunk.choices.first().and_then(|choice| choice.delta.content.clone()) {
+                        None => { continue; }
+                        Some(s) if ARGS.get().unwrap().sanitize_output => { sanitize(&s) }
+                        Some(s) => { s }
+                    };
+                    partial.push_str(&delta);
+                    delta
+                }
+                Err(err) => {
+                    // 工具调用的参数无法接着生成,只续写普通内容
+                    if tool_calls.is_empty() {
+                        if let Some(stream) = resume.reconnect(&partial, &err).await {
+                            upstream = stream;
+                            continue;
+                        }
+                    } else {
+                        upstream::report(&err, resume.server_key);
+                    }
+                    debug!("stream interrupted after {} chars: {}", partial.len(), err);
+                    publisher.send(Some("error"), envelope(json!({
+                        "message":format!("{}",err),
+                        "code":500u16,
+                        "partial":partial,
+                    })).to_string());
+                    audit(500, usage.as_ref(), &partial);
+                    return;
+                }
+            };
+            if let Some(text) = coalescer.push(delta.clone()) {
+                publisher.send(None, envelope(json!({
+                    "message":text,
+                    "code":200u16,
+                })).to_string());
+            }
+            if params.progress {
+                // 按增量累加,避免每次重新计算整段内容
+                tokens += tokenizer::count_tokens(model, &delta);
+                if tokens >= reported_tokens + PROGRESS_STEP {
+                    reported_tokens = tokens;
+                    publisher.send(Some("progress"), progress_event(tokens));
+                }
+            }
+        }
+        if let Some(text) = coalescer.take() {
+            publisher.send(None, envelope(json!({
+                "message":text,
+                "code":200u16,
+            })).to_string());
+        }
+        if params.progress && tokens != reported_tokens {
+            publisher.send(Some("progress"), progress_event(tokens));
+        }
+        if !tool_calls.is_empty() {
+            publisher.send(Some("tool_calls"), envelope(json!({
+                "code":200u16,
+                "tool_calls":tool_calls.into_json(),
+            })).to_string());
+        }
+        if params.final_event {
+            let mut body = json!({
+                "message":partial,
+                "code":200u16,
+                "usage":usage,
+                "finish_reason":finish_reason,
+            });
+            if let Some(cost) = cost {
+                body["cost_usd"] = json!(cost);
+            }
+            publisher.send(Some("final"), envelope(body).to_string());
+        }
+        audit(200, usage.as_ref(), &partial);
+    }.instrument(span));
+
+    sse(events)
+}
+
+fn sse(events: impl Stream<Item = Result<Event, Infallible>> + Send + 'static) -> Response {
+    let sse = Sse::new(events);
+    // 长时间没有新内容时发送注释心跳,避免被中间代理断开
+    match ARGS.get().unwrap().sse_keepalive {
+        0 => { sse.into_response() }
+        secs => { sse.keep_alive(KeepAlive::new().interval(Duration::from_secs(secs)).text("keepalive")).into_response() }
+    }
+}
+
+fn progress_event(tokens: usize) -> String {
+    envelope(json!({
+        "code":200u16,
+        "type":"progress",
+        "tokens":tokens,
+    })).to_string()
+}
+
+// 纯文本流式返回,只输出生成的文本,没有 SSE 或 JSON 包装;出错、取消或服务退出时直接断开连接
+pub async fn text_handler(Extension(request_id): Extension<RequestId>, Extension(peer): Extension<Peer>, headers: HeaderMap, ChatInput(input): ChatInput) -> Response {
+    let owner = request_id::owner(&input.api_key, peer);
+    let Upstream { input, stream: mut upstream, mut resume, active, permit, span } = match open(input, false, &headers, &request_id.0).await {
+        Ok(s) => { s }
+        Err(response) => { return response; }
+    };
+    let breaker = CIRCUIT_BREAKER.get().unwrap();
+
+    let (tx, rx) = mpsc::channel::<Result<String, Infallible>>(16);
+    let mut cancellation = cancel::register(&request_id.0, owner);
+    tokio::spawn(async move {
+        let _active = active;
+        let _permit = permit;
+        let audit = |code: u16, partial: &str| {
+            audit::record(&request_id.0, &input, code, None, Some(partial).filter(|partial| !partial.is_empty()));
+        };
+        let mut partial = String::new();
+        let mut first_token = first_token_deadline();
+        let shutdown = shutdown::grace_expired();
+        tokio::pin!(shutdown);
+        loop {
+            let chunk = tokio::select! {
+                chunk = upstream.next() => {
+                    match chunk {
+                        None => {
+                            audit(200, &partial);
+                            return;
+                        }
+                        Some(chunk) => { chunk }
+                    }
+                }
+                Ok(()) = &mut cancellation.receiver => {
+                    debug!("stream cancelled");
+                    audit(499, &partial);
+                    return;
+                }
+                () = &mut shutdown => {
+                    debug!("stream terminated by shutdown");
+                    audit(503, &partial);
+                    return;
+                }
+                () = idle_timeout() => {
+                    breaker.on_failure();
+                    debug!("stream idle timeout");
+                    audit(504, &partial);
+                    return;
+                }
+                () = first_token_timeout(first_token) => {
+                    breaker.on_failure();
+                    debug!("no first token within {}s", ARGS.get().unwrap().first_token_timeout);
+                    audit(504, &partial);
+                    return;
+                }
+            };
+            let delta = match chunk {
+                Ok(chunk) => {
+                    match chunk.choices.first().and_then(|choice| choice.delta.content.clone()) {
+                        None => { continue; }
+                        Some(s) if ARGS.get().unwrap().sanitize_output => { sanitize(&s) }
+                        Some(s) => { s }
+                    }
+                }
+                Err(err) => {
+                    if let Some(stream) = resume.reconnect(&partial, &err).await {
+                        upstream = stream;
+                        continue;
+                    }
+                    debug!("stream interrupted: {}", err);
+                    audit(500, &partial);
+                    return;
+                }
+            };
+            if !delta.is_empty() {
+                first_token = None;
+            }
+            partial.push_str(&delta);
+            if tx.send(Ok(delta)).await.is_err() {
+                // 客户端已断开
+                audit(499, &partial);
+                return;
+            }
+        }
+    }.instrument(span));
+
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], StreamBody::new(ReceiverStream::new(rx))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(value: Value) -> Vec<ChatCompletionMessageToolCallChunk> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn tool_call_arguments_split_across_deltas() {
+        let mut tool_calls = ToolCalls::default();
+        tool_calls.push(&chunks(json!([
+            {"index":0,"id":"call_a","type":"function","function":{"name":"get_weather","arguments":""}},
+        ])));
+        tool_calls.push(&chunks(json!([{"index":0,"function":{"arguments":"{\"city\":"}}])));
+        tool_calls.push(&chunks(json!([
+            {"index":1,"id":"call_b","type":"function","function":{"name":"get_time","arguments":"{\"tz\""}},
+        ])));
+        tool_calls.push(&chunks(json!([
+            {"index":0,"function":{"arguments":"\"Paris\"}"}},
+            {"index":1,"function":{"arguments":":\"UTC\"}"}},
+        ])));
+        assert_eq!(tool_calls.into_json(), vec![
+            json!({"id":"call_a","type":"function","function":{"name":"get_weather","arguments":"{\"city\":\"Paris\"}"}}),
+            json!({"id":"call_b","type":"function","function":{"name":"get_time","arguments":"{\"tz\":\"UTC\"}"}}),
+        ]);
+    }
+}
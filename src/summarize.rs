@@ -0,0 +1,167 @@
+use async_openai::types::CompletionUsage;
+use axum::http::HeaderMap;
+use axum::{Extension, Json};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::{ARGS, audit, build_chat_request, build_config, CIRCUIT_BREAKER, default_model, envelope, extract, injection, messages, model_allowed, resolve_api_key, status, THROTTLE, tokenizer, upstream, UserInput};
+use crate::provider::UpstreamConfig;
+use crate::request_id::RequestId;
+
+const MAP_PROMPT: &str = "Summarize the following text:\n\n";
+const REDUCE_PROMPT: &str = "Combine the following summaries into a single summary:\n\n";
+
+// {"text":"<长文本>","model":"gpt-3.5-turbo","max_tokens":512}
+#[derive(Deserialize, Debug)]
+pub struct SummarizeInput {
+    #[serde(default)]
+    pub api_key: String,
+    #[serde(default)]
+    pub model: String,
+    pub max_tokens: Option<u32>,
+    pub text: String,
+}
+
+// map-reduce 摘要: 按 --chunk-size 个 token 切分,分别摘要后合并;
+// 合并后的内容仍超过 --chunk-size 时继续切分摘要,直到能在一次请求中完成
+pub async fn summarize_handler(Extension(request_id): Extension<RequestId>, headers: HeaderMap, Json(mut value): Json<Value>) -> Json<Value> {
+    if let Err(response) = extract::restrict_fields(&mut value, &["text"]) {
+        return response;
+    }
+    let input: SummarizeInput = match serde_json::from_value(value) {
+        Ok(input) => { input }
+        Err(err) => {
+            return Json(envelope(json!({
+                "message":format!("{}",err),
+                "code":400u16,
+            })));
+        }
+    };
+    let _active = status::track();
+    let model = if input.model.is_empty() { default_model() } else { input.model.clone() };
+    // 审计日志按一次请求记录,contents 为待摘要的原文
+    let audit_input: UserInput = match serde_json::from_value(json!({
+        "api_key":input.api_key,
+        "model":model,
+        "contents":[{"role":"user","content":input.text}],
+    })) {
+        Ok(s) => { s }
+        Err(err) => {
+            return Json(envelope(json!({
+                "message":format!("{}",err),
+                "code":500u16,
+            })));
+        }
+    };
+    if let Some(phrase) = injection::scan(&audit_input) {
+        return Json(envelope(audit::log(&request_id.0, &audit_input, json!({
+            "message":messages::get("prompt_injection"),
+            "code":403u16,
+            "phrase":phrase,
+        }))));
+    }
+    Json(envelope(audit::log(&request_id.0, &audit_input, summarize(&input, model, &headers).await)))
+}
+
+async fn summarize(input: &SummarizeInput, model: String, headers: &HeaderMap) -> Value {
+    if !model_allowed(&model) {
+        return json!({
+            "message":format!("model {} is not allowed",model),
+            "code":403u16,
+        });
+    }
+    let api_key = match resolve_api_key(&input.api_key) {
+        None => {
+            return json!({
+                "message":messages::get("api_key_empty"),
+                "code":500u16,
+            });
+        }
+        Some(key) => { key }
+    };
+    let config = build_config(api_key, &model).forward(headers);
+    let chunk_size = ARGS.get().unwrap().chunk_size as usize;
+
+    let mut usage = CompletionUsage { prompt_tokens: 0, completion_tokens: 0, total_tokens: 0, prompt_tokens_details: None, completion_tokens_details: None };
+    let mut calls = 0;
+    let mut chunks = tokenizer::split_tokens(&model, &input.text, chunk_size);
+    let chunk_count = chunks.len();
+    let mut prompt = MAP_PROMPT;
+    while chunks.len() > 1 {
+        debug!("summarizing {} chunks", chunks.len());
+        let results: Vec<Result<(String, Option<CompletionUsage>), Value>> = futures::stream::iter(std::mem::take(&mut chunks))
+            .map(|chunk| complete(&config, &input.api_key, &model, None, format!("{}{}", prompt, chunk)))
+            .buffered(ARGS.get().unwrap().summarize_concurrency.max(1))
+            .collect()
+            .await;
+        let mut summaries = Vec::new();
+        for result in results {
+            calls += 1;
+            match result {
+                Ok((summary, used)) => {
+                    add_usage(&mut usage, used);
+                    summaries.push(summary);
+                }
+                Err(err) => { return err; }
+            }
+        }
+        let count = summaries.len();
+        chunks = tokenizer::split_tokens(&model, &summaries.join("\n\n"), chunk_size);
+        // 摘要没有变少时继续下去不会结束
+        if chunks.len() >= count {
+            return json!({
+                "message":format!("summaries do not fit in --chunk-size {}", chunk_size),
+                "code":500u16,
+            });
+        }
+        prompt = REDUCE_PROMPT;
+    }
+    let text = chunks.pop().unwrap_or_default();
+    let (summary, used) = match complete(&config, &input.api_key, &model, input.max_tokens, format!("{}{}", prompt, text)).await {
+        Ok(s) => { s }
+        Err(err) => { return err; }
+    };
+    calls += 1;
+    add_usage(&mut usage, used);
+    json!({
+        "message":summary,
+        "code":200u16,
+        "chunks":chunk_count,
+        "calls":calls,
+        "usage":usage,
+    })
+}
+
+// 每次请求与 /chat 一样先经过熔断和限流等待,熔断打开后剩下的分块不再请求;失败时返回错误响应
+async fn complete(config: &UpstreamConfig, api_key: &str, model: &str, max_tokens: Option<u32>, prompt: String) -> Result<(String, Option<CompletionUsage>), Value> {
+    let error = |message: String| json!({"message":message,"code":500u16});
+    let input: UserInput = serde_json::from_value(json!({
+        "api_key":api_key,
+        "model":model,
+        "max_tokens":max_tokens,
+        "contents":[{"role":"user","content":prompt}],
+    })).map_err(|err| error(format!("{}", err)))?;
+    let request = build_chat_request(&input, &[]).map_err(|err| error(format!("{}", err)))?;
+    if !CIRCUIT_BREAKER.get().unwrap().allow() {
+        return Err(json!({"message":messages::get("upstream_unavailable"),"code":503u16}));
+    }
+    let throttle = THROTTLE.get().unwrap();
+    throttle.wait().await;
+    let (response, headers) = upstream::create_chat_completion_with_retry(config, &request, &input).await
+        .map_err(|err| error(format!("{}", err)))?;
+    throttle.update(&headers);
+    let content = response.choices.first()
+        .and_then(|choice| choice.message.content.clone())
+        .ok_or_else(|| error(messages::get("no_content")))?;
+    Ok((content, response.usage))
+}
+
+fn add_usage(total: &mut CompletionUsage, usage: Option<CompletionUsage>) {
+    if let Some(usage) = usage {
+        total.prompt_tokens += usage.prompt_tokens;
+        total.completion_tokens += usage.completion_tokens;
+        total.total_tokens += usage.total_tokens;
+    }
+}
@@ -0,0 +1,17 @@
+use anyhow::Result;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, runtime};
+use opentelemetry_sdk::trace::TracerProvider;
+
+pub fn init_tracer_provider(endpoint: &str) -> Result<TracerProvider> {
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new("service.name", env!("CARGO_PKG_NAME"))]))
+        .build();
+    Ok(provider)
+}
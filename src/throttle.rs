@@ -0,0 +1,122 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use reqwest::header::HeaderMap;
+use tracing::debug;
+
+// 重置时间的上限,异常的响应头(如很大的数)按此处理
+const MAX_RESET: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Debug, Default)]
+struct Limits {
+    remaining_requests: Option<u64>,
+    reset_at: Option<Instant>,
+}
+
+// 根据 OpenAI 返回的 x-ratelimit-* 响应头,在剩余请求数较低时提前等待
+#[derive(Debug)]
+pub struct Throttle {
+    threshold: u64,
+    max_wait: Duration,
+    limits: Mutex<Limits>,
+    waiting: AtomicUsize,
+}
+
+impl Throttle {
+    pub fn new(threshold: u64, max_wait: Duration) -> Self {
+        Throttle {
+            threshold,
+            max_wait,
+            limits: Mutex::new(Limits::default()),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn update(&self, headers: &HeaderMap) {
+        let remaining = headers.get("x-ratelimit-remaining-requests")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        let reset = headers.get("x-ratelimit-reset-requests")
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_reset);
+        if remaining.is_none() && reset.is_none() {
+            return;
+        }
+        let mut limits = self.limits.lock();
+        limits.remaining_requests = remaining;
+        limits.reset_at = reset.map(|reset| Instant::now() + reset);
+    }
+
+    pub async fn wait(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let delay = {
+            let limits = self.limits.lock();
+            match (limits.remaining_requests, limits.reset_at) {
+                (Some(remaining), Some(reset_at)) if remaining <= self.threshold => {
+                    reset_at.saturating_duration_since(Instant::now()).min(self.max_wait)
+                }
+                _ => { return; }
+            }
+        };
+        if !delay.is_zero() {
+            debug!("rate limit nearly exhausted, waiting {:?}", delay);
+            self.waiting.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(delay).await;
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // 过了重置时间的数据已失效,返回 None
+    pub fn remaining_requests(&self) -> Option<u64> {
+        let limits = self.limits.lock();
+        match limits.reset_at {
+            Some(reset_at) if reset_at <= Instant::now() => { None }
+            _ => { limits.remaining_requests }
+        }
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+}
+
+// 解析 "1s", "6m0s", "59.52s", "20ms" 这类时长,超过 MAX_RESET 时按 MAX_RESET
+fn parse_reset(s: &str) -> Option<Duration> {
+    let mut total = 0f64;
+    let mut rest = s.trim();
+    while !rest.is_empty() {
+        let idx = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let value: f64 = rest[..idx].parse().ok()?;
+        rest = &rest[idx..];
+        let (secs, len) = if rest.starts_with("ms") {
+            (value / 1000.0, 2)
+        } else if rest.starts_with('h') {
+            (value * 3600.0, 1)
+        } else if rest.starts_with('m') {
+            (value * 60.0, 1)
+        } else if rest.starts_with('s') {
+            (value, 1)
+        } else {
+            return None;
+        };
+        total += secs;
+        rest = &rest[len..];
+    }
+    Some(Duration::try_from_secs_f64(total).ok().map_or(MAX_RESET, |reset| reset.min(MAX_RESET)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_reset_is_capped() {
+        assert_eq!(parse_reset("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_reset("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_reset(&format!("{}s", "9".repeat(400))), Some(MAX_RESET));
+        assert_eq!(parse_reset("1e400s"), None);
+    }
+}
@@ -0,0 +1,77 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use clap::ValueEnum;
+use rustls::{Certificate, PrivateKey, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion};
+use serde::Serialize;
+use tracing::debug;
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum TlsVersion {
+    #[value(name = "1.2")]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+// 未指定 --tls-cert 时返回 None,使用明文 HTTP
+pub fn server_config(cert: Option<&str>, key: Option<&str>, min_version: TlsVersion, ciphers: &[String]) -> Result<Option<RustlsConfig>> {
+    let (cert, key) = match (cert, key) {
+        (None, None) => { return Ok(None); }
+        (Some(cert), Some(key)) => { (cert, key) }
+        _ => { return Err(anyhow!("--tls-cert and --tls-key must be given together")); }
+    };
+    let versions: &[&SupportedProtocolVersion] = match min_version {
+        TlsVersion::Tls12 => { &[&rustls::version::TLS13, &rustls::version::TLS12] }
+        TlsVersion::Tls13 => { &[&rustls::version::TLS13] }
+    };
+    let suites = cipher_suites(ciphers)?;
+    debug!("tls: min version {:?}, cipher suites {:?}", min_version, suites.iter().map(|suite| suite.suite()).collect::<Vec<_>>());
+    let mut config = ServerConfig::builder()
+        .with_cipher_suites(&suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .map_err(|err| anyhow!("tls: {} (check --tls-ciphers against --min-tls-version)", err))?
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert)?, load_key(key)?)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Some(RustlsConfig::from_config(Arc::new(config))))
+}
+
+// 名称与 rustls 一致,如 TLS13_AES_256_GCM_SHA384;为空时使用 rustls 的默认列表
+fn cipher_suites(names: &[String]) -> Result<Vec<SupportedCipherSuite>> {
+    if names.is_empty() {
+        return Ok(rustls::DEFAULT_CIPHER_SUITES.to_vec());
+    }
+    names.iter().map(|name| {
+        rustls::ALL_CIPHER_SUITES.iter()
+            .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name.trim()))
+            .copied()
+            .ok_or_else(|| anyhow!("tls: unknown cipher suite {}", name))
+    }).collect()
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(anyhow!("tls: no certificate found in {}", path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<PrivateKey> {
+    for item in rustls_pemfile::read_all(&mut BufReader::new(File::open(path)?))? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => {
+                return Ok(PrivateKey(key));
+            }
+            _ => {}
+        }
+    }
+    Err(anyhow!("tls: no private key found in {}", path))
+}
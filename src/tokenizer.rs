@@ -0,0 +1,57 @@
+use tiktoken_rs::{bpe_for_model, cl100k_base_singleton, CoreBPE};
+
+// 未知模型按 cl100k_base 计算
+fn bpe(model: &str) -> &'static CoreBPE {
+    bpe_for_model(model).unwrap_or_else(|_| cl100k_base_singleton())
+}
+
+// 模型的上下文长度,未知模型返回 None
+pub fn context_size(model: &str) -> Option<usize> {
+    tiktoken_rs::model::get_context_size(model)
+}
+
+pub fn count_tokens(model: &str, text: &str) -> usize {
+    bpe(model).encode_ordinary(text).len()
+}
+
+// 超过 max_tokens 时返回截断后的内容
+pub fn truncate_tokens(model: &str, text: &str, max_tokens: usize) -> Option<String> {
+    let bpe = bpe(model);
+    let tokens = bpe.encode_ordinary(text);
+    if tokens.len() <= max_tokens {
+        return None;
+    }
+    let bytes = bpe.decode_bytes(&tokens[..max_tokens]).ok()?;
+    // 截断处可能落在多字节字符中间
+    Some(String::from_utf8_lossy(&bytes).trim_end_matches('\u{FFFD}').to_string())
+}
+
+// 按行把文本切成每段不超过 size 个 token 的片段,单行过长时在 token 边界处切开
+pub fn split_tokens(model: &str, text: &str, size: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_tokens = 0;
+    for line in text.split_inclusive('\n') {
+        let mut line = line;
+        let mut line_tokens = count_tokens(model, line);
+        if current_tokens + line_tokens > size && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+            current_tokens = 0;
+        }
+        while line_tokens > size {
+            let head = match truncate_tokens(model, line, size) {
+                Some(head) if !head.is_empty() => { head }
+                _ => { break; }
+            };
+            line = &line[head.len()..];
+            chunks.push(head);
+            line_tokens = count_tokens(model, line);
+        }
+        current.push_str(line);
+        current_tokens += line_tokens;
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
@@ -0,0 +1,249 @@
+use async_openai::config::Config;
+use async_openai::error::{ApiError, OpenAIError};
+use async_openai::types::{ChatCompletionStreamOptions, CompletionUsage, CreateChatCompletionRequest, CreateChatCompletionResponse, CreateChatCompletionStreamResponse};
+use futures::StreamExt;
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use serde_json::json;
+
+use std::time::Duration;
+
+use tracing::{Instrument, Span, warn};
+use tracing::field::Empty;
+
+use crate::{CIRCUIT_BREAKER, http_client, request_retries, RETRY_BUDGET, UserInput};
+
+#[derive(Deserialize, Debug)]
+struct WrappedError {
+    error: ApiError,
+}
+
+// 与 client.chat().create() 相同的请求,但同时返回响应头(用于读取限流信息)
+pub async fn create_chat_completion<C: Config>(config: &C, request: &CreateChatCompletionRequest) -> Result<(CreateChatCompletionResponse, HeaderMap), OpenAIError> {
+    let response = http_client()
+        .post(config.url("/chat/completions"))
+        .query(&config.query())
+        .headers(config.headers())
+        .json(request)
+        .send()
+        .await?;
+    let status = response.status();
+    let headers = response.headers().clone();
+    let bytes = response.bytes().await?;
+    if !status.is_success() {
+        let wrapped: WrappedError = serde_json::from_slice(&bytes).map_err(OpenAIError::JSONDeserialize)?;
+        return Err(OpenAIError::ApiError(wrapped.error));
+    }
+    let response = serde_json::from_slice(&bytes).map_err(OpenAIError::JSONDeserialize)?;
+    Ok((response, headers))
+}
+
+// 每次上游调用一个 chat_completion span(--otlp-endpoint 时导出),记录模型、metadata 和 token 用量
+pub fn span(model: &str, input: &UserInput) -> Span {
+    let span = tracing::info_span!("chat_completion", model, metadata = Empty, prompt_tokens = Empty, completion_tokens = Empty, total_tokens = Empty);
+    if let Some(metadata) = &input.metadata {
+        span.record("metadata", json!(metadata).to_string());
+    }
+    span
+}
+
+pub fn record_usage(span: &Span, usage: Option<&CompletionUsage>) {
+    if let Some(usage) = usage {
+        span.record("prompt_tokens", usage.prompt_tokens);
+        span.record("completion_tokens", usage.completion_tokens);
+        span.record("total_tokens", usage.total_tokens);
+    }
+}
+
+// 失败时按请求的 max_retries 重试,每次重试需要从全局重试预算中取得令牌,熔断打开时不再重试;
+// 重试都失败后才向熔断报告一次结果
+pub async fn create_chat_completion_with_retry<C: Config>(config: &C, request: &CreateChatCompletionRequest, input: &UserInput) -> Result<(CreateChatCompletionResponse, HeaderMap), OpenAIError> {
+    let span = span(&request.model, input);
+    let result = retry(config, request, request_retries(input), input.api_key.is_empty()).instrument(span.clone()).await;
+    if let Ok((response, _)) = &result {
+        record_usage(&span, response.usage.as_ref());
+    }
+    result
+}
+
+// server_key 为是否使用服务端密钥,见 upstream_fault
+async fn retry<C: Config>(config: &C, request: &CreateChatCompletionRequest, max_retries: u32, server_key: bool) -> Result<(CreateChatCompletionResponse, HeaderMap), OpenAIError> {
+    let breaker = CIRCUIT_BREAKER.get().unwrap();
+    let mut attempt = 0;
+    loop {
+        let err = match create_chat_completion(config, request).await {
+            Ok(s) => {
+                breaker.on_success();
+                return Ok(s);
+            }
+            Err(err) => { err }
+        };
+        if attempt < max_retries && retryable(&err) && breaker.allow() {
+            if RETRY_BUDGET.get().unwrap().try_acquire() {
+                attempt += 1;
+                warn!("upstream error, retry {}/{}: {}", attempt, max_retries, err);
+                tokio::time::sleep(Duration::from_millis(200 * attempt as u64)).await;
+                continue;
+            }
+            warn!("retry budget exhausted: {}", err);
+        }
+        report(&err, server_key);
+        return Err(err);
+    }
+}
+
+// 以流式请求上游,把生成的内容累积到 partial 中,结束后拼成与非流式相同的响应;
+// 调用方超时取消时 partial 中保留已生成的部分。结果与非流式一样报告给熔断
+pub async fn create_chat_completion_streamed<C: Config>(config: &C, request: &CreateChatCompletionRequest, input: &UserInput, partial: &mut String) -> Result<(CreateChatCompletionResponse, HeaderMap), OpenAIError> {
+    let span = span(&request.model, input);
+    let result = stream_chat_completion(config, request, partial).instrument(span.clone()).await;
+    match &result {
+        Ok((response, _)) => {
+            CIRCUIT_BREAKER.get().unwrap().on_success();
+            record_usage(&span, response.usage.as_ref());
+        }
+        Err(err) => { report(err, input.api_key.is_empty()); }
+    }
+    result
+}
+
+// 直接读取 SSE 而不经过 client.chat().create_stream(),以便拿到响应头(用于读取限流信息)
+async fn stream_chat_completion<C: Config>(config: &C, request: &CreateChatCompletionRequest, partial: &mut String) -> Result<(CreateChatCompletionResponse, HeaderMap), OpenAIError> {
+    let mut request = request.clone();
+    request.stream = Some(true);
+    request.stream_options = Some(ChatCompletionStreamOptions { include_usage: true });
+    let response = http_client()
+        .post(config.url("/chat/completions"))
+        .query(&config.query())
+        .headers(config.headers())
+        .json(&request)
+        .send()
+        .await?;
+    let status = response.status();
+    let headers = response.headers().clone();
+    if !status.is_success() {
+        let bytes = response.bytes().await?;
+        let wrapped: WrappedError = serde_json::from_slice(&bytes).map_err(OpenAIError::JSONDeserialize)?;
+        return Err(OpenAIError::ApiError(wrapped.error));
+    }
+    let mut body = response.bytes_stream();
+    let mut buffer = Vec::new();
+    let mut response = json!({"object":"chat.completion","choices":[]});
+    let mut finish_reason = None;
+    'body: while let Some(bytes) = body.next().await {
+        buffer.extend_from_slice(&bytes?);
+        while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim_end().strip_prefix("data:") else { continue; };
+            let data = data.trim_start();
+            if data == "[DONE]" {
+                break 'body;
+            }
+            let chunk: CreateChatCompletionStreamResponse = serde_json::from_str(data).map_err(OpenAIError::JSONDeserialize)?;
+            response["id"] = json!(chunk.id);
+            response["created"] = json!(chunk.created);
+            response["model"] = json!(chunk.model);
+            response["service_tier"] = json!(chunk.service_tier);
+            response["system_fingerprint"] = json!(chunk.system_fingerprint);
+            if let Some(usage) = chunk.usage {
+                response["usage"] = json!(usage);
+            }
+            if let Some(choice) = chunk.choices.first() {
+                if let Some(content) = &choice.delta.content {
+                    partial.push_str(content);
+                }
+                if choice.finish_reason.is_some() {
+                    finish_reason = choice.finish_reason;
+                }
+            }
+        }
+    }
+    response["choices"] = json!([{
+        "index":0,
+        "message":{"role":"assistant","content":partial},
+        "finish_reason":finish_reason,
+    }]);
+    Ok((serde_json::from_value(response).map_err(OpenAIError::JSONDeserialize)?, headers))
+}
+
+fn retryable(err: &OpenAIError) -> bool {
+    match err {
+        OpenAIError::Reqwest(_) => true,
+        OpenAIError::ApiError(err) => {
+            !matches!(err.r#type.as_deref(), Some("invalid_request_error") | Some("insufficient_quota"))
+        }
+        _ => false,
+    }
+}
+
+// 只有上游自身的问题(连接失败、超时、5xx)计入熔断;429 限流只在使用服务端密钥(server_key)时计入,
+// 客户端自己的 api_key 额度用完不影响其他客户端。参数错误、上下文超长、api_key 无效等由客户端导致的错误不计入
+pub fn upstream_fault(err: &OpenAIError, server_key: bool) -> bool {
+    match err {
+        OpenAIError::Reqwest(_) | OpenAIError::JSONDeserialize(_) => true,
+        OpenAIError::ApiError(err) => {
+            let rate_limited = matches!(err.r#type.as_deref(), Some("requests") | Some("tokens"))
+                || err.code.as_deref() == Some("rate_limit_exceeded");
+            err.r#type.as_deref() == Some("server_error") || (server_key && rate_limited)
+        }
+        // 流式请求的错误只有文字描述,状态码从 "Invalid status code: 429 Too Many Requests" 中取出
+        OpenAIError::StreamError(message) => {
+            match message.strip_prefix("Invalid status code: ") {
+                Some(rest) => { rest.starts_with('5') || (server_key && rest.starts_with("429")) }
+                None => { true }
+            }
+        }
+        _ => false,
+    }
+}
+
+// 上游出错时向熔断报告: 上游自身的问题计为失败;其余错误说明上游有正常响应,计为成功,
+// 否则半开状态下的探测请求遇到参数错误等时熔断会一直停在半开
+pub fn report(err: &OpenAIError, server_key: bool) {
+    let breaker = CIRCUIT_BREAKER.get().unwrap();
+    if upstream_fault(err, server_key) {
+        breaker.on_failure();
+    } else {
+        breaker.on_success();
+    }
+}
+
+pub fn is_context_length_exceeded(err: &OpenAIError) -> bool {
+    matches!(err, OpenAIError::ApiError(err) if err.code.as_deref() == Some("context_length_exceeded"))
+}
+
+// 从 "This model's maximum context length is 8192 tokens. ..." 中取出上下文长度
+pub fn max_context(err: &OpenAIError) -> Option<usize> {
+    let OpenAIError::ApiError(err) = err else { return None; };
+    let rest = &err.message[err.message.find("maximum context length is ")? + "maximum context length is ".len()..];
+    rest.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(r#type: &str, code: Option<&str>) -> OpenAIError {
+        OpenAIError::ApiError(ApiError {
+            message: String::new(),
+            r#type: Some(r#type.to_string()),
+            param: None,
+            code: code.map(str::to_string),
+        })
+    }
+
+    #[test]
+    fn only_upstream_errors_are_faults() {
+        assert!(upstream_fault(&api_error("server_error", None), false));
+        assert!(upstream_fault(&api_error("requests", Some("rate_limit_exceeded")), true));
+        assert!(!upstream_fault(&api_error("requests", Some("rate_limit_exceeded")), false));
+        assert!(!upstream_fault(&api_error("invalid_request_error", Some("context_length_exceeded")), true));
+        assert!(!upstream_fault(&api_error("invalid_request_error", Some("invalid_api_key")), true));
+        assert!(upstream_fault(&OpenAIError::StreamError("Invalid status code: 502 Bad Gateway".to_string()), false));
+        assert!(upstream_fault(&OpenAIError::StreamError("Invalid status code: 429 Too Many Requests".to_string()), true));
+        assert!(!upstream_fault(&OpenAIError::StreamError("Invalid status code: 429 Too Many Requests".to_string()), false));
+        assert!(!upstream_fault(&OpenAIError::StreamError("Invalid status code: 401 Unauthorized".to_string()), true));
+        assert!(upstream_fault(&OpenAIError::StreamError("Stream ended".to_string()), true));
+    }
+}